@@ -1,7 +1,13 @@
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+
 use crate::api::RubyMutator;
+use crate::weak_proc::finalizers::RubyFinalizable;
 use crate::{extra_assert, upcalls, Ruby};
 use mmtk::scheduler::GCWorker;
 use mmtk::util::api_util::NullableObjectReference;
+use mmtk::util::constants::BYTES_IN_WORD;
 use mmtk::util::{Address, ObjectReference, VMMutatorThread, VMWorkerThread};
 
 // For the C binding
@@ -9,14 +15,172 @@ pub const OBJREF_OFFSET: usize = 8;
 pub const MIN_OBJ_ALIGN: usize = 8; // Even on 32-bit machine.  A Ruby object is at least 40 bytes large.
 
 pub const GC_THREAD_KIND_WORKER: libc::c_int = 1;
+/// The kind of the thread that calls deferred `obj_free`.  The VM must define the same value, and
+/// accept it in `init_gc_worker_thread`.
+pub const GC_THREAD_KIND_FINALIZER: libc::c_int = 2;
 
 pub const HIDDEN_SIZE_MASK: usize = 0x0000FFFFFFFFFFFF;
 
+/// The hidden header holds the log2 of the alignment of the payload in the bits above
+/// `HIDDEN_SIZE_MASK`.  Zero means `MIN_OBJ_ALIGN`.
+pub const HIDDEN_LOG_ALIGN_SHIFT: usize = 48;
+pub const HIDDEN_LOG_ALIGN_MASK: usize = 0x000F000000000000;
+
 pub const MMTK_WEAK_CONCURRENT_SET_KIND_FSTRING: u8 = 0;
 pub const MMTK_WEAK_CONCURRENT_SET_KIND_GLOBAL_SYMBOLS: u8 = 1;
 
+/// The value of the (object or field) unlog bit when the write barrier needs to take the slow path.
+pub const LOG_BIT_UNLOGGED: u8 = 1;
+
 pub(crate) const RUBY_IMMEDIATE_MASK: usize = 0x07;
 
+pub(crate) const RUBY_T_MASK: usize = 0x1f;
+
+/// Names of `enum ruby_value_type`, indexed by the `T_*` value in the flags of an object.
+const RUBY_VALUE_TYPE_NAMES: [&CStr; RUBY_T_MASK + 1] = [
+    c"T_NONE",
+    c"T_OBJECT",
+    c"T_CLASS",
+    c"T_MODULE",
+    c"T_FLOAT",
+    c"T_STRING",
+    c"T_REGEXP",
+    c"T_ARRAY",
+    c"T_HASH",
+    c"T_STRUCT",
+    c"T_BIGNUM",
+    c"T_FILE",
+    c"T_DATA",
+    c"T_MATCH",
+    c"T_COMPLEX",
+    c"T_RATIONAL",
+    c"T_UNKNOWN_0x10",
+    c"T_NIL",
+    c"T_TRUE",
+    c"T_FALSE",
+    c"T_SYMBOL",
+    c"T_FIXNUM",
+    c"T_UNDEF",
+    c"T_UNKNOWN_0x17",
+    c"T_UNKNOWN_0x18",
+    c"T_UNKNOWN_0x19",
+    c"T_IMEMO",
+    c"T_NODE",
+    c"T_ICLASS",
+    c"T_ZOMBIE",
+    c"T_MOVED",
+    c"T_UNKNOWN_0x1f",
+];
+
+/// Get the name of a `T_*` builtin type, such as `"T_STRING"`.
+pub fn ruby_value_type_name(builtin_type: usize) -> &'static CStr {
+    RUBY_VALUE_TYPE_NAMES[builtin_type & RUBY_T_MASK]
+}
+
+pub(crate) const RUBY_T_IMEMO: usize = 0x1a;
+pub(crate) const RUBY_FL_USHIFT: usize = 12;
+pub(crate) const RUBY_IMEMO_MASK: usize = 0x0f;
+
+/// Make the name of an imemo subtype, such as `"T_IMEMO:env"`, from the name the VM gives, or
+/// `None` if the VM doesn't know the subtype.
+fn make_imemo_type_name(imemo_type: usize, vm_name: Option<&CStr>) -> CString {
+    let name = match vm_name {
+        Some(vm_name) => format!("T_IMEMO:{}", vm_name.to_string_lossy()),
+        None => format!("T_IMEMO:unknown_{imemo_type:#x}"),
+    };
+    CString::new(name).unwrap()
+}
+
+/// Get the names of all imemo subtypes from the VM, indexed by the imemo subtype in the flags of a
+/// `T_IMEMO` object.  Called once when the binding is initialized.
+pub(crate) fn get_imemo_type_names(upcalls: &RubyUpcalls) -> Box<[CString]> {
+    (0..=RUBY_IMEMO_MASK)
+        .map(|imemo_type| {
+            let vm_name = (upcalls.get_imemo_type_name)(imemo_type);
+            let vm_name = (!vm_name.is_null()).then(|| unsafe { CStr::from_ptr(vm_name) });
+            make_imemo_type_name(imemo_type, vm_name)
+        })
+        .collect()
+}
+
+/// Get the name of an imemo subtype, such as `"T_IMEMO:env"`.
+pub fn ruby_imemo_type_name(imemo_type: usize) -> &'static CStr {
+    &crate::binding().imemo_type_names[imemo_type & RUBY_IMEMO_MASK]
+}
+
+fn make_type_descriptor(name: &CStr) -> Box<[i8]> {
+    name.to_bytes().iter().map(|&b| b as i8).collect()
+}
+
+/// Type descriptors returned by `ObjectModel::get_type_descriptor`.  Each descriptor holds the
+/// bytes of a type name, such as `"T_STRING"` or `"T_IMEMO:env"`, without the NUL terminator.
+pub(crate) struct TypeDescriptors {
+    builtin: Box<[Box<[i8]>]>,
+    imemo: Box<[Box<[i8]>]>,
+}
+
+impl TypeDescriptors {
+    pub fn new(imemo_type_names: &[CString]) -> Self {
+        Self {
+            builtin: RUBY_VALUE_TYPE_NAMES
+                .iter()
+                .map(|name| make_type_descriptor(name))
+                .collect(),
+            imemo: imemo_type_names
+                .iter()
+                .map(|name| make_type_descriptor(name))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, builtin_type: usize, imemo_type: Option<usize>) -> &[i8] {
+        match imemo_type {
+            Some(imemo_type) => &self.imemo[imemo_type & RUBY_IMEMO_MASK],
+            None => &self.builtin[builtin_type & RUBY_T_MASK],
+        }
+    }
+}
+
+/// Class names obtained from the VM.  The VM only guarantees the string it returns to be valid
+/// until the next allocation or GC, so the binding copies each distinct name once and hands out
+/// pointers to its own copy, which lives as long as the binding.
+#[derive(Default)]
+pub(crate) struct ClassNames {
+    names: Mutex<HashSet<Box<CStr>>>,
+}
+
+impl ClassNames {
+    /// Get the binding-owned copy of `name`, copying it if it has not been seen.
+    pub fn intern(&self, name: &CStr) -> &CStr {
+        let mut names = self.names.lock().unwrap();
+        if !names.contains(name) {
+            names.insert(name.into());
+        }
+        let interned: *const CStr = &**names.get(name).unwrap();
+        // unsafe: The boxed string is never removed or moved, so it outlives the lock guard.
+        unsafe { &*interned }
+    }
+}
+
+/// The value of `RubyObjectType::imemo_type` if the object is not a `T_IMEMO`.
+pub const IMEMO_TYPE_NONE: usize = usize::MAX;
+
+/// The type of an object, as returned by `mmtk_get_object_type`.
+#[repr(C)]
+pub struct RubyObjectType {
+    /// The `T_*` builtin type.
+    pub builtin_type: usize,
+    /// The imemo subtype if `builtin_type` is `T_IMEMO`, or `IMEMO_TYPE_NONE` otherwise.
+    pub imemo_type: usize,
+    /// The class of the object.
+    pub klass: VALUE,
+    /// A static NUL-terminated string that names the builtin type and the imemo subtype.
+    pub name: *const libc::c_char,
+    /// The name of the class, or null if the class is anonymous or hidden.  It is owned by the
+    /// binding and remains valid until the binding is destroyed.
+    pub class_name: *const libc::c_char,
+}
+
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct VALUE(pub usize);
@@ -25,6 +189,14 @@ impl VALUE {
     pub fn is_special_const(&self) -> bool {
         self.0 == 0 || self.0 & RUBY_IMMEDIATE_MASK != 0
     }
+
+    /// Get the object it refers to, or `None` if it is a special constant.
+    pub fn as_object_reference(&self) -> Option<ObjectReference> {
+        // unsafe: Non-special-const VALUEs are non-zero.
+        (!self.is_special_const()).then(|| unsafe {
+            ObjectReference::from_raw_address_unchecked(Address::from_usize(self.0))
+        })
+    }
 }
 
 impl From<ObjectReference> for VALUE {
@@ -45,7 +217,7 @@ pub struct HiddenHeader {
 impl HiddenHeader {
     #[inline(always)]
     pub fn is_sane(&self) -> bool {
-        self.prefix & !HIDDEN_SIZE_MASK == 0
+        self.prefix & !(HIDDEN_SIZE_MASK | HIDDEN_LOG_ALIGN_MASK) == 0
     }
 
     #[inline(always)]
@@ -61,6 +233,38 @@ impl HiddenHeader {
         self.assert_sane();
         self.prefix & HIDDEN_SIZE_MASK
     }
+
+    /// The alignment of the payload requested when the object was allocated.
+    pub fn payload_align(&self) -> usize {
+        self.assert_sane();
+        let log_align = (self.prefix & HIDDEN_LOG_ALIGN_MASK) >> HIDDEN_LOG_ALIGN_SHIFT;
+        if log_align == 0 {
+            MIN_OBJ_ALIGN
+        } else {
+            1 << log_align
+        }
+    }
+
+    /// Compose a hidden header.  `align` must be a power of two.
+    pub fn new(payload_size: usize, align: usize) -> Self {
+        assert!(
+            payload_size & !HIDDEN_SIZE_MASK == 0,
+            "Payload too large: {payload_size}"
+        );
+        assert!(align.is_power_of_two(), "Bad alignment: {align}");
+        let log_align = if align <= MIN_OBJ_ALIGN {
+            0
+        } else {
+            align.trailing_zeros() as usize
+        };
+        assert!(
+            (log_align << HIDDEN_LOG_ALIGN_SHIFT) & !HIDDEN_LOG_ALIGN_MASK == 0,
+            "Alignment too large: {align}"
+        );
+        Self {
+            prefix: payload_size | (log_align << HIDDEN_LOG_ALIGN_SHIFT),
+        }
+    }
 }
 
 /// Provide convenient methods for accessing Ruby objects.
@@ -103,6 +307,36 @@ impl RubyObjectAccess {
         self.hidden_header().payload_size()
     }
 
+    /// The alignment of the payload, which must be preserved when the object is moved.
+    pub fn payload_align(&self) -> usize {
+        self.hidden_header().payload_align()
+    }
+
+    /// The alignment of the start of the object (including the hidden header) when copied.
+    pub fn align_when_copied(&self) -> usize {
+        self.payload_align()
+    }
+
+    /// The offset from the start of the object to the address that needs to be aligned to
+    /// `align_when_copied`.  When the payload needs more than `MIN_OBJ_ALIGN`, it is the payload,
+    /// not the hidden header, that must be aligned.
+    pub fn align_offset_when_copied(&self) -> usize {
+        if self.payload_align() > MIN_OBJ_ALIGN {
+            Self::prefix_size()
+        } else {
+            0
+        }
+    }
+
+    /// Get the raw word of the hidden header without checking its sanity.
+    pub fn hidden_header_prefix(&self) -> usize {
+        self.hidden_header().prefix
+    }
+
+    pub fn is_hidden_header_sane(&self) -> bool {
+        self.hidden_header().is_sane()
+    }
+
     fn flags_field(&self) -> Address {
         self.objref.to_raw_address()
     }
@@ -111,6 +345,65 @@ impl RubyObjectAccess {
         unsafe { self.flags_field().load::<usize>() }
     }
 
+    /// Get the `T_*` builtin type from the flags.
+    pub fn builtin_type(&self) -> usize {
+        self.load_flags() & RUBY_T_MASK
+    }
+
+    /// Get the imemo subtype if the object is a `T_IMEMO`.
+    pub fn imemo_type(&self) -> Option<usize> {
+        let flags = self.load_flags();
+        (flags & RUBY_T_MASK == RUBY_T_IMEMO).then_some((flags >> RUBY_FL_USHIFT) & RUBY_IMEMO_MASK)
+    }
+
+    /// Load the `klass` field of `struct RBasic`, which follows the flags.
+    pub fn load_klass(&self) -> VALUE {
+        unsafe { (self.flags_field() + BYTES_IN_WORD).load::<VALUE>() }
+    }
+
+    /// Get the name of the class of the object from the VM, or `None` if the class is anonymous
+    /// or hidden.  The name is copied into binding-owned storage.  This calls into the VM, so
+    /// don't call it while the world is stopped.
+    pub fn class_name(&self) -> Option<&'static CStr> {
+        let klass = self.load_klass();
+        if klass.is_special_const() {
+            return None;
+        }
+        let name = (upcalls().get_class_name)(klass);
+        (!name.is_null()).then(|| {
+            crate::binding()
+                .class_names
+                .intern(unsafe { CStr::from_ptr(name) })
+        })
+    }
+
+    /// Get the name of the builtin type, including the imemo subtype for `T_IMEMO`.
+    pub fn type_name(&self) -> &'static CStr {
+        match self.imemo_type() {
+            Some(imemo_type) => ruby_imemo_type_name(imemo_type),
+            None => ruby_value_type_name(self.builtin_type()),
+        }
+    }
+
+    /// Get the type descriptor for `ObjectModel::get_type_descriptor`.
+    pub fn type_descriptor(&self) -> &'static [i8] {
+        crate::binding()
+            .type_descriptors
+            .get(self.builtin_type(), self.imemo_type())
+    }
+
+    pub fn object_type(&self) -> RubyObjectType {
+        RubyObjectType {
+            builtin_type: self.builtin_type(),
+            imemo_type: self.imemo_type().unwrap_or(IMEMO_TYPE_NONE),
+            klass: self.load_klass(),
+            name: self.type_name().as_ptr(),
+            class_name: self
+                .class_name()
+                .map_or(std::ptr::null(), |name| name.as_ptr()),
+        }
+    }
+
     pub fn has_exivar(&self) -> bool {
         (upcalls().has_exivar)(self.objref)
     }
@@ -237,6 +530,11 @@ impl GCThreadTLS {
         Self::new(GC_THREAD_KIND_WORKER, gc_context as *mut libc::c_void)
     }
 
+    /// For the thread that calls `obj_free` in the background.  It is not a GC worker.
+    pub fn for_finalizer() -> Self {
+        Self::new(GC_THREAD_KIND_FINALIZER, std::ptr::null_mut())
+    }
+
     pub fn from_vwt(vwt: VMWorkerThread) -> *mut GCThreadTLS {
         unsafe { std::mem::transmute(vwt) }
     }
@@ -320,11 +618,42 @@ impl From<Vec<ObjectReference>> for RawVecOfObjRef {
     }
 }
 
+#[repr(C)]
+#[derive(Clone)]
+pub struct RawVecOfFinalizable {
+    pub ptr: *mut RubyFinalizable,
+    pub len: usize,
+    pub capa: usize,
+}
+
+impl RawVecOfFinalizable {
+    pub fn from_vec(vec: Vec<RubyFinalizable>) -> RawVecOfFinalizable {
+        let mut vec = std::mem::ManuallyDrop::new(vec);
+        let (ptr, len, capa) = (vec.as_mut_ptr(), vec.len(), vec.capacity());
+
+        RawVecOfFinalizable { ptr, len, capa }
+    }
+
+    /// # Safety
+    ///
+    /// This function turns raw pointer into a Vec without check.
+    pub unsafe fn into_vec(self) -> Vec<RubyFinalizable> {
+        unsafe { Vec::from_raw_parts(self.ptr, self.len, self.capa) }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone)]
 pub struct RubyBindingOptions {
     pub ractor_check_mode: bool,
     pub suffix_size: usize,
+    /// True if the VM installs return barriers and reports stack watermarks with
+    /// `mmtk_set_stack_watermark`, so that nursery GCs only scan the part of the VM stack newer
+    /// than the watermark.  See `crate::stack_watermark`.
+    pub return_barrier: bool,
+    /// The offset of the referent field from the object reference of a weak reference object
+    /// registered with `mmtk_register_weak_reference`.
+    pub weak_ref_referent_offset: usize,
 }
 
 #[repr(C)]
@@ -354,6 +683,8 @@ pub struct RubyUpcalls {
     pub scan_global_tbl_roots: extern "C" fn(),
     pub scan_yjit_roots: extern "C" fn(),
     pub scan_global_symbols_roots: extern "C" fn(),
+    /// Scan the finalizer table of the VM.  It is empty if the VM registers finalizers with
+    /// `mmtk_add_finalizer`.
     pub scan_finalizer_tbl_roots: extern "C" fn(),
     pub scan_misc_roots: extern "C" fn(),
     pub scan_final_jobs_roots: extern "C" fn(),
@@ -417,6 +748,89 @@ pub struct RubyUpcalls {
     pub after_updating_jit_code: extern "C" fn(),
     // Weak reference processing
     pub handle_weak_references: extern "C" fn(object: ObjectReference, is_moving: bool),
+    // Finalization
+    pub schedule_finalization: extern "C" fn(tls: VMWorkerThread),
+    /// Like `scan_roots_in_mutator_thread`, but skip the frames older than `watermark`, the value
+    /// reported with `mmtk_set_stack_watermark`.  Only called in nursery GCs when
+    /// `RubyBindingOptions::return_barrier` is set and the thread has a watermark.
+    pub scan_roots_in_mutator_thread_partial:
+        extern "C" fn(mutator_tls: VMMutatorThread, worker_tls: VMWorkerThread, watermark: usize),
+    /// Get the name of an imemo subtype (`enum imemo_type`), or null if it is not a valid subtype.
+    pub get_imemo_type_name: extern "C" fn(imemo_type: usize) -> *const libc::c_char,
+    /// Get the name of a class, or null if it is anonymous or hidden.  It may be called during GC,
+    /// so it must not allocate objects.
+    pub get_class_name: extern "C" fn(klass: VALUE) -> *const libc::c_char,
 }
 
 unsafe impl Sync for RubyUpcalls {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imemo_type_names_use_vm_names() {
+        assert_eq!(
+            make_imemo_type_name(0, Some(c"env")).as_c_str(),
+            c"T_IMEMO:env"
+        );
+        assert_eq!(
+            make_imemo_type_name(0xe, None).as_c_str(),
+            c"T_IMEMO:unknown_0xe"
+        );
+    }
+
+    #[test]
+    fn type_descriptors_are_type_names() {
+        let imemo_type_names = [CString::new("T_IMEMO:env").unwrap()];
+        let descriptors = TypeDescriptors::new(&imemo_type_names);
+        let as_i8 = |s: &str| s.bytes().map(|b| b as i8).collect::<Vec<_>>();
+        assert_eq!(descriptors.get(0x05, None), as_i8("T_STRING"));
+        assert_eq!(descriptors.get(RUBY_T_IMEMO, Some(0)), as_i8("T_IMEMO:env"));
+    }
+
+    #[test]
+    fn class_names_are_copied_once() {
+        let class_names = ClassNames::default();
+        let vm_name = CString::new("Foo").unwrap();
+        let first = class_names.intern(&vm_name);
+        drop(vm_name);
+        assert_eq!(first, c"Foo");
+        let second = class_names.intern(c"Foo");
+        assert_eq!(first.as_ptr(), second.as_ptr());
+    }
+
+    #[test]
+    fn hidden_header_keeps_size_and_align() {
+        let header = HiddenHeader::new(40, MIN_OBJ_ALIGN);
+        assert_eq!(header.prefix, 40);
+        assert_eq!(header.payload_size(), 40);
+        assert_eq!(header.payload_align(), MIN_OBJ_ALIGN);
+
+        // Smaller alignments are rounded up to `MIN_OBJ_ALIGN`.
+        assert_eq!(HiddenHeader::new(40, 1).payload_align(), MIN_OBJ_ALIGN);
+
+        for align in [16, 64, 4096, 1 << 15] {
+            let header = HiddenHeader::new(HIDDEN_SIZE_MASK, align);
+            assert!(header.is_sane());
+            assert_eq!(header.payload_size(), HIDDEN_SIZE_MASK);
+            assert_eq!(header.payload_align(), align);
+        }
+    }
+
+    #[test]
+    fn hidden_header_rejects_bits_above_align() {
+        assert!(HiddenHeader {
+            prefix: HIDDEN_LOG_ALIGN_MASK
+        }
+        .is_sane());
+        assert!(!HiddenHeader { prefix: 1 << 52 }.is_sane());
+        assert!(!HiddenHeader { prefix: usize::MAX }.is_sane());
+    }
+
+    #[test]
+    #[should_panic(expected = "Alignment too large")]
+    fn hidden_header_rejects_large_align() {
+        HiddenHeader::new(40, 1 << 16);
+    }
+}
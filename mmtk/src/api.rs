@@ -6,12 +6,20 @@ use std::ffi::CStr;
 
 use crate::abi;
 use crate::abi::HiddenHeader;
+use crate::abi::RawVecOfFinalizable;
 use crate::abi::RawVecOfObjRef;
 use crate::abi::RubyBindingOptions;
 use crate::binding;
 use crate::binding::RubyBinding;
+use crate::copy_failure::TryCopyFailureStats;
+use crate::deferred_obj_free::DeferredObjFreeStats;
+use crate::histogram::ObjectHistogram;
 use crate::mmtk;
+use crate::object_model::VMObjectModel;
+use crate::weak_proc::finalizers::RubyFinalizable;
+use crate::weak_proc::weak_refs::SoftRefStats;
 use crate::Ruby;
+use crate::RubyMemorySlice;
 use crate::RubySlot;
 use mmtk::memory_manager;
 use mmtk::memory_manager::mmtk_init;
@@ -23,6 +31,7 @@ use mmtk::util::options::GCTriggerSelector;
 use mmtk::util::options::PlanSelector;
 use mmtk::util::{Address, ObjectReference};
 use mmtk::util::{VMMutatorThread, VMThread};
+use mmtk::vm::ObjectModel;
 use mmtk::AllocationSemantics;
 use mmtk::MMTKBuilder;
 use mmtk::Mutator;
@@ -37,6 +46,7 @@ pub type RubyMutator = Mutator<Ruby>;
 pub extern "C" fn mmtk_builder_default() -> *mut MMTKBuilder {
     let mut builder = MMTKBuilder::new_no_env_vars();
     // We don't use the Java-style finalization framework in mmtk-core.
+    // `ObjectSpace.define_finalizer` is implemented in `weak_proc::finalizers`.
     builder.options.no_finalizer.set(true);
     Box::into_raw(Box::new(builder))
 }
@@ -127,6 +137,14 @@ pub unsafe extern "C" fn mmtk_init_binding(
 
     let builder = unsafe { Box::from_raw(builder) };
     let binding_options = unsafe { &*binding_options };
+
+    let plan = *builder.options.plan;
+    assert!(
+        !is_plan_unsupported(plan),
+        "The {plan:?} plan cannot pin objects in the spaces it evacuates, \
+        but Ruby reports conservatively scanned roots as pinning roots.  See README.md."
+    );
+
     let mmtk_boxed = mmtk_init(&builder);
     let mmtk_static = Box::leak(Box::new(mmtk_boxed));
 
@@ -137,13 +155,29 @@ pub unsafe extern "C" fn mmtk_init_binding(
         .unwrap_or_else(|_| panic!("Binding is already initialized"));
 }
 
+/// Return true if `plan` cannot be used with Ruby because it cannot pin objects in some spaces it
+/// evacuates.
+fn is_plan_unsupported(plan: PlanSelector) -> bool {
+    matches!(
+        plan,
+        PlanSelector::SemiSpace
+            | PlanSelector::GenCopy
+            | PlanSelector::GenImmix
+            | PlanSelector::MarkCompact
+    )
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_bind_mutator(tls: VMMutatorThread) -> *mut RubyMutator {
-    Box::into_raw(memory_manager::bind_mutator(mmtk(), tls))
+    let mutator = Box::into_raw(memory_manager::bind_mutator(mmtk(), tls));
+    binding().stack_watermarks.add(mutator);
+    mutator
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn mmtk_destroy_mutator(mutator: *mut RubyMutator) {
+    binding().stack_watermarks.remove(mutator);
+    binding().weak_proc.flush_current_obj_free_buffer();
     let mut boxed_mutator = unsafe { Box::from_raw(mutator) };
     memory_manager::destroy_mutator(boxed_mutator.as_mut())
 }
@@ -190,6 +224,7 @@ pub extern "C" fn mmtk_initialize_collection(tls: VMThread) {
 pub extern "C" fn mmtk_prepare_to_fork() {
     mmtk().prepare_to_fork();
     binding().join_all_gc_threads();
+    binding().deferred_obj_free.stop_thread();
 }
 
 #[no_mangle]
@@ -232,9 +267,11 @@ pub extern "C" fn mmtk_total_bytes() -> usize {
     memory_manager::total_bytes(mmtk())
 }
 
+/// Query if `object` is reachable in the current GC.  When MarkCompact forwards weak tables,
+/// dead entries have already been removed, and this returns true.
 #[no_mangle]
 pub extern "C" fn mmtk_is_reachable(object: ObjectReference) -> bool {
-    object.is_reachable()
+    binding().weak_proc.is_forwarding_only() || object.is_reachable()
 }
 
 #[no_mangle]
@@ -260,7 +297,25 @@ pub extern "C" fn mmtk_handle_user_collection_request(
     force: bool,
     exhaustive: bool,
 ) {
+    handle_user_collection_request(tls, force, exhaustive, exhaustive);
+}
+
+/// Trigger a user-requested GC.  `exhaustive` makes generational plans do a full-heap GC, and
+/// `clear_soft_refs` lets the GC clear soft references.
+fn handle_user_collection_request(
+    tls: VMMutatorThread,
+    force: bool,
+    exhaustive: bool,
+    clear_soft_refs: bool,
+) {
+    let weak_refs = &binding().weak_proc.weak_refs;
+    if clear_soft_refs {
+        weak_refs.request_exhaustive_gc();
+    }
     crate::mmtk().handle_user_collection_request(tls, force, exhaustive);
+    // The GC has finished if one was triggered.  If the request was ignored, the next GC is not
+    // exhaustive.
+    weak_refs.cancel_exhaustive_gc_request();
 }
 
 #[no_mangle]
@@ -294,6 +349,29 @@ pub unsafe extern "C" fn mmtk_add_obj_free_candidates(objects: *const ObjectRefe
     binding().weak_proc.add_obj_free_candidates(objects_slice)
 }
 
+/// Declare the builtin types whose `obj_free` can be called by multiple GC threads concurrently.
+/// Bit `T_xxx` of `mask` represents the type `T_xxx`.  Dead objects of other types are freed by
+/// one GC thread at a time.
+#[no_mangle]
+pub extern "C" fn mmtk_set_obj_free_parallel_safe_types(mask: u32) {
+    binding().weak_proc.set_obj_free_parallel_safe_types(mask)
+}
+
+/// Declare the builtin types whose `obj_free` can be called on the finalizer thread while mutators
+/// are running, when deferred `obj_free` is enabled with `RUBY_MMTK_DEFERRED_OBJ_FREE`.  Bit
+/// `T_xxx` of `mask` represents the type `T_xxx`.  Dead objects of other types are freed by GC
+/// threads while the world is stopped.  No type is deferrable by default.
+#[no_mangle]
+pub extern "C" fn mmtk_set_obj_free_deferrable_types(mask: u32) {
+    binding().deferred_obj_free.set_deferrable_types(mask)
+}
+
+/// Get the statistics of `obj_free` deferred to the finalizer thread.
+#[no_mangle]
+pub extern "C" fn mmtk_get_deferred_obj_free_stats() -> DeferredObjFreeStats {
+    binding().deferred_obj_free.stats()
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_get_all_obj_free_candidates() -> RawVecOfObjRef {
     let vec = binding().weak_proc.get_all_obj_free_candidates();
@@ -305,6 +383,47 @@ pub extern "C" fn mmtk_free_raw_vec_of_obj_ref(raw_vec: RawVecOfObjRef) {
     unsafe { raw_vec.into_vec() };
 }
 
+/// Register `object` for finalization.  `finalizers` is the array of finalizer procs, and is kept
+/// alive while `object` is registered.
+#[no_mangle]
+pub extern "C" fn mmtk_add_finalizer(object: ObjectReference, finalizers: ObjectReference) {
+    binding()
+        .weak_proc
+        .finalizers
+        .add(RubyFinalizable { object, finalizers })
+}
+
+/// Get one object that has become unreachable and is ready for finalization.  Return false if
+/// there are none.
+#[no_mangle]
+pub extern "C" fn mmtk_get_finalized_object(out: *mut RubyFinalizable) -> bool {
+    if let Some(finalizable) = binding().weak_proc.finalizers.get_ready() {
+        unsafe { out.write(finalizable) };
+        true
+    } else {
+        false
+    }
+}
+
+/// Unregister and return all finalizable objects, including those ready for finalization.  Used
+/// when the VM runs all finalizers at exit.
+#[no_mangle]
+pub extern "C" fn mmtk_get_all_finalizers() -> RawVecOfFinalizable {
+    RawVecOfFinalizable::from_vec(binding().weak_proc.finalizers.get_all())
+}
+
+/// Unregister and return the finalizers of `object`.  Used by `ObjectSpace.undefine_finalizer`
+/// and when the VM replaces the finalizer procs of an object.
+#[no_mangle]
+pub extern "C" fn mmtk_get_finalizers_for(object: ObjectReference) -> RawVecOfFinalizable {
+    RawVecOfFinalizable::from_vec(binding().weak_proc.finalizers.get_for(object))
+}
+
+#[no_mangle]
+pub extern "C" fn mmtk_free_raw_vec_of_finalizable(raw_vec: RawVecOfFinalizable) {
+    unsafe { raw_vec.into_vec() };
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_register_ppp(object: ObjectReference) {
     crate::binding().ppp_registry.register(object)
@@ -316,10 +435,23 @@ pub unsafe extern "C" fn mmtk_register_ppps(objects: *const ObjectReference, len
     crate::binding().ppp_registry.register_many(objects_slice)
 }
 
+/// Report the stack watermark of `mutator`.  The VM calls this after installing a return barrier
+/// at the frame boundary `watermark` when scanning the stack, and when moving the watermark to an
+/// older frame.  `MMTK_STACK_WATERMARK_NONE` means the whole stack needs scanning.
+#[no_mangle]
+pub extern "C" fn mmtk_set_stack_watermark(mutator: *mut RubyMutator, watermark: usize) {
+    binding().stack_watermarks.set(mutator, watermark)
+}
+
+/// Get the stack watermark of `mutator`, or `MMTK_STACK_WATERMARK_NONE` if not reported.
+#[no_mangle]
+pub extern "C" fn mmtk_get_stack_watermark(mutator: *mut RubyMutator) -> usize {
+    binding().stack_watermarks.get(mutator)
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_get_backwarded_object(object: ObjectReference) -> ObjectReference {
-    let backwarding_table = crate::binding().backwarding_table.lock().unwrap();
-    backwarding_table.get(&object).copied().unwrap_or(object)
+    crate::binding().backwarding_table.get(object)
 }
 
 #[no_mangle]
@@ -333,6 +465,55 @@ pub extern "C" fn mmtk_get_vo_bit_base() -> usize {
     mmtk::util::metadata::side_metadata::vo_bit_side_metadata_addr().as_usize()
 }
 
+/// Get the base address of the object-granularity unlog bits.  Each bit covers
+/// `1 << mmtk_get_log_bit_log_region_size()` bytes, and is `MMTK_LOG_BIT_UNLOGGED` if the
+/// write barrier of the object needs to take the slow path.
+#[no_mangle]
+pub extern "C" fn mmtk_get_log_bit_base() -> usize {
+    crate::log_bit::log_bit_spec()
+        .get_absolute_offset()
+        .as_usize()
+}
+
+/// Get the log2 of the number of bytes covered by each object-granularity unlog bit.  The bit of
+/// address `a` is bit `(a >> size) % 8` of the byte at `base + (a >> size) / 8`, where `base` is
+/// `mmtk_get_log_bit_base()`.
+#[no_mangle]
+pub extern "C" fn mmtk_get_log_bit_log_region_size() -> usize {
+    crate::log_bit::log_bit_spec().log_bytes_in_region
+}
+
+/// Get the base address of the field-granularity unlog bits.  Like the object-granularity unlog
+/// bits, but each bit covers one field.  Only valid if the current plan uses field-granularity
+/// barriers.
+#[no_mangle]
+pub extern "C" fn mmtk_get_field_unlog_bit_base() -> usize {
+    crate::log_bit::field_unlog_bit_spec()
+        .get_absolute_offset()
+        .as_usize()
+}
+
+/// Get the log2 of the number of bytes covered by each field-granularity unlog bit.  The bit is
+/// located the same way as the object-granularity unlog bit, using
+/// `mmtk_get_field_unlog_bit_base()` as the base.
+#[no_mangle]
+pub extern "C" fn mmtk_get_field_unlog_bit_log_region_size() -> usize {
+    crate::log_bit::field_unlog_bit_spec().log_bytes_in_region
+}
+
+/// Check if the inlined barrier fast path agrees with mmtk-core when writing to `object`.  Pass
+/// the address of the written field as `slot` if the VM inlines the field-granularity check, or
+/// null if it inlines the object-granularity check.
+///
+/// It compares the object unlog bit (and the field unlog bit if `slot` is not null) computed
+/// inline with mmtk-core.  It only reads metadata, so it can be called at any time, and does not
+/// change what the next GC sees.  The VM should call this in debug builds.
+#[no_mangle]
+pub extern "C" fn mmtk_log_bit_self_check(object: ObjectReference, slot: Address) -> bool {
+    let slot = (!slot.is_zero()).then_some(slot);
+    crate::log_bit::self_check(object, slot)
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_gc_poll(tls: VMMutatorThread) {
     mmtk::memory_manager::gc_poll(mmtk(), tls)
@@ -374,6 +555,11 @@ pub extern "C" fn mmtk_is_object_wb_unprotected(object: ObjectReference) -> bool
     crate::binding().is_object_wb_unprotected(object)
 }
 
+/// The object-remembering post-barrier.  Call this after writing any field of `object`.
+///
+/// It does not tell MMTk which field is written or what is written, so it only works with plans
+/// that remember whole objects, such as StickyImmix.  Use `mmtk_object_field_write_post` if the
+/// slot and the new target are known.
 #[no_mangle]
 pub unsafe extern "C" fn mmtk_object_reference_write_post(
     mutator: *mut RubyMutator,
@@ -389,6 +575,100 @@ pub unsafe extern "C" fn mmtk_object_reference_write_post(
     )
 }
 
+/// The pre-barrier for writing `target` into the field at `slot` of `object`.  Call this before
+/// the field is overwritten.  Snapshot-at-the-beginning barriers read the old value of the field,
+/// so there is no variant without `slot`.
+///
+/// `target` is the VALUE to be written, or null if it is not a heap object.
+#[no_mangle]
+pub unsafe extern "C" fn mmtk_object_field_write_pre(
+    mutator: *mut RubyMutator,
+    object: ObjectReference,
+    slot: Address,
+    target: NullableObjectReference,
+) {
+    mmtk::memory_manager::object_reference_write_pre(
+        unsafe { &mut *mutator },
+        object,
+        RubySlot::from_address(slot),
+        target.into(),
+    )
+}
+
+/// The post-barrier for writing `target` into the field at `slot` of `object`.  Call this after
+/// the field is written.  Unlike `mmtk_object_reference_write_post`, it gives MMTk the precise
+/// slot, which field-granularity barriers need.
+///
+/// `target` is the VALUE that has been written, or null if it is not a heap object.
+#[no_mangle]
+pub unsafe extern "C" fn mmtk_object_field_write_post(
+    mutator: *mut RubyMutator,
+    object: ObjectReference,
+    slot: Address,
+    target: NullableObjectReference,
+) {
+    mmtk::memory_manager::object_reference_write_post(
+        unsafe { &mut *mutator },
+        object,
+        RubySlot::from_address(slot),
+        target.into(),
+    )
+}
+
+/// The slow path of the field write barrier.  The parameters are the same as
+/// `mmtk_object_field_write_post`.
+///
+/// If the fast path of the barrier is inlined in C (or JIT-compiled code), call this when the
+/// fast path finds that the object or the field is unlogged.  It skips the fast-path check in
+/// Rust.
+#[no_mangle]
+pub unsafe extern "C" fn mmtk_object_field_write_slow(
+    mutator: *mut RubyMutator,
+    object: ObjectReference,
+    slot: Address,
+    target: NullableObjectReference,
+) {
+    let mutator = unsafe { &mut *mutator };
+    mutator
+        .barrier
+        .object_reference_write_slow(object, RubySlot::from_address(slot), target.into())
+}
+
+/// The pre-barrier for copying `len` VALUEs from `src` to `dst`.  Call this before copying
+/// elements in bulk, such as in `Array#replace`, `Array#concat` or `MEMCPY` of VALUE arrays.
+///
+/// `src_obj` and `dst_obj` are the objects that contain the source and the destination arrays,
+/// or null if unknown.
+#[no_mangle]
+pub unsafe extern "C" fn mmtk_array_copy_pre(
+    mutator: *mut RubyMutator,
+    src_obj: NullableObjectReference,
+    src: Address,
+    dst_obj: NullableObjectReference,
+    dst: Address,
+    len: usize,
+) {
+    let src_slice = RubyMemorySlice::new(src_obj.into(), src, len);
+    let dst_slice = RubyMemorySlice::new(dst_obj.into(), dst, len);
+    mmtk::memory_manager::memory_region_copy_pre(unsafe { &mut *mutator }, src_slice, dst_slice)
+}
+
+/// The post-barrier for copying `len` VALUEs from `src` to `dst`.  Call this after copying
+/// elements in bulk.  The parameters are the same as `mmtk_array_copy_pre`.
+#[no_mangle]
+pub unsafe extern "C" fn mmtk_array_copy_post(
+    mutator: *mut RubyMutator,
+    src_obj: NullableObjectReference,
+    src: Address,
+    dst_obj: NullableObjectReference,
+    dst: Address,
+    len: usize,
+) {
+    let src_slice = RubyMemorySlice::new(src_obj.into(), src, len);
+    let dst_slice = RubyMemorySlice::new(dst_obj.into(), dst, len);
+    mmtk::memory_manager::memory_region_copy_post(unsafe { &mut *mutator }, src_slice, dst_slice)
+}
+
 /// Enumerate objects.  This function will call `callback(object, data)` for each object. It has
 /// undefined behavior if allocation or GC happens while this function is running.
 #[no_mangle]
@@ -407,6 +687,56 @@ pub unsafe extern "C" fn mmtk_hidden_header_is_sane(hidden_header: *const Hidden
     hidden_header.is_sane()
 }
 
+/// Compose the hidden header of an object whose payload is `payload_size` bytes and aligned to
+/// `align`.  The VM should pass the same `align` to `mmtk_alloc`, with `offset` being
+/// `MMTK_OBJREF_OFFSET` if `align` is greater than `MMTK_MIN_OBJ_ALIGN`, so that the payload is
+/// aligned.  The alignment is preserved when the object is moved.
+#[no_mangle]
+pub extern "C" fn mmtk_hidden_header_new(payload_size: usize, align: usize) -> HiddenHeader {
+    HiddenHeader::new(payload_size, align)
+}
+
+/// Get the builtin type, the imemo subtype and the class of an object.
+#[no_mangle]
+pub extern "C" fn mmtk_get_object_type(object: ObjectReference) -> abi::RubyObjectType {
+    abi::RubyObjectAccess::from_objref(object).object_type()
+}
+
+/// Request a per-type object histogram, and trigger a full-heap GC to collect it.  Call
+/// `mmtk_object_histogram` after this function returns to get the result.  The GC does not clear
+/// soft references, so that taking a histogram does not change what is live.
+#[no_mangle]
+pub extern "C" fn mmtk_request_object_histogram(tls: VMMutatorThread) {
+    binding().object_histogram.request();
+    handle_user_collection_request(tls, true, true, false);
+}
+
+/// Copy the object histogram collected in the last full-heap GC that collected one into `out`.
+/// Return false if no histogram has been collected, yet.  Only objects that survived that GC are
+/// counted, and free slots are not reported as `T_NONE`.
+#[no_mangle]
+pub unsafe extern "C" fn mmtk_object_histogram(out: *mut ObjectHistogram) -> bool {
+    match binding().object_histogram.last_histogram() {
+        Some(histogram) => {
+            unsafe { out.write(histogram) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Get the cumulative statistics of `try_copy` failures, including injected ones.
+#[no_mangle]
+pub extern "C" fn mmtk_get_try_copy_failure_stats() -> TryCopyFailureStats {
+    binding().try_copy_failure.stats()
+}
+
+/// Log an object for debugging.  Intended to be called from gdb.
+#[no_mangle]
+pub extern "C" fn mmtk_dump_object(object: ObjectReference) {
+    VMObjectModel::dump_object(object)
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_current_gc_may_move_object() -> bool {
     crate::mmtk().get_plan().current_gc_may_move_object()
@@ -424,3 +754,52 @@ pub extern "C" fn mmtk_current_gc_is_nursery() -> bool {
 pub extern "C" fn mmtk_declare_weak_references(obj: ObjectReference) {
     crate::binding().weak_proc.declare_weak_references(obj)
 }
+
+/// Register `slot` as a weak slot owned by `obj`.  `slot` is either inside `obj` or outside the
+/// GC heap.  It is cleared to `Qfalse` when the object in it dies, and updated when the object in
+/// it moves.  The registration is removed when `obj` dies.  Return false, and do nothing, if
+/// `slot` is already registered.
+#[no_mangle]
+pub extern "C" fn mmtk_register_weak_slot(obj: ObjectReference, slot: Address) -> bool {
+    crate::binding().weak_proc.weak_slots.register(obj, slot)
+}
+
+/// Unregister a weak slot registered with `mmtk_register_weak_slot`.  Call it before the owner
+/// frees or reuses an off-heap slot.  Return false if `slot` is not registered.
+#[no_mangle]
+pub extern "C" fn mmtk_unregister_weak_slot(slot: Address) -> bool {
+    crate::binding().weak_proc.weak_slots.unregister(slot)
+}
+
+/// Register a weak reference object.  Its referent is held in the field at
+/// `weak_ref_referent_offset` bytes from `reference` (see `RubyBindingOptions`), and is cleared
+/// to `Qfalse` when the referent dies.
+#[no_mangle]
+pub extern "C" fn mmtk_register_weak_reference(reference: ObjectReference) {
+    crate::binding().weak_proc.weak_refs.register(reference)
+}
+
+/// Register a soft reference object.  It has the same layout as weak reference objects, but its
+/// referent is retained unless the GC is an emergency or exhaustive GC, or the heap is under
+/// pressure.
+#[no_mangle]
+pub extern "C" fn mmtk_register_soft_reference(reference: ObjectReference) {
+    crate::binding()
+        .weak_proc
+        .weak_refs
+        .register_soft(reference)
+}
+
+/// Get the statistics of soft references cleared by GC.
+#[no_mangle]
+pub extern "C" fn mmtk_get_soft_ref_stats() -> SoftRefStats {
+    crate::binding().weak_proc.weak_refs.soft_ref_stats()
+}
+
+/// Register an ephemeron.  Its key and value are held in the fields at `MMTK_EPHEMERON_KEY_OFFSET`
+/// and `MMTK_EPHEMERON_VALUE_OFFSET` bytes from `ephemeron`.  The value is kept alive only while
+/// the key is reachable.  Both are cleared to `Qfalse` when the key dies.
+#[no_mangle]
+pub extern "C" fn mmtk_register_ephemeron(ephemeron: ObjectReference) {
+    crate::binding().weak_proc.ephemerons.register(ephemeron)
+}
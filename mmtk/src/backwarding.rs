@@ -0,0 +1,136 @@
+//! The "backwarding" table that maps the new addresses of some moved objects to their old
+//! addresses.
+//!
+//! Ruby looks up the generic fields (exivar) of an object using its address.  When an object with
+//! exivar is moved, the VM needs its old address to find its entry in the generic fields table
+//! until the table itself is updated.
+//!
+//! GC workers insert into the table in parallel during evacuation.  To avoid serializing them on
+//! one lock, the table is split into shards, each protected by its own lock.  Objects are assigned
+//! to shards by their new addresses.
+//!
+//! Entries are added whenever an object with exivar is copied, including young objects evacuated
+//! by nursery GCs if the nursery is moving.  The table is cleared in `post_forwarding` at the end
+//! of every GC, so entries never outlive the GC that created them.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use mmtk::util::ObjectReference;
+
+use crate::abi::MIN_OBJ_ALIGN;
+
+pub struct BackwardingTable {
+    shards: Box<[Mutex<HashMap<ObjectReference, ObjectReference>>]>,
+}
+
+impl BackwardingTable {
+    /// Create a table with at least `num_shards` shards.  The number is rounded up to a power of
+    /// two.
+    pub fn new(num_shards: usize) -> Self {
+        let num_shards = num_shards.max(1).next_power_of_two();
+        Self {
+            shards: (0..num_shards).map(|_| Default::default()).collect(),
+        }
+    }
+
+    fn shard_of(
+        &self,
+        to_obj: ObjectReference,
+    ) -> &Mutex<HashMap<ObjectReference, ObjectReference>> {
+        // Adjacent objects are likely to be copied by the same worker, so mix the bits a little to
+        // spread them among shards.
+        let index = to_obj.to_raw_address().as_usize() / MIN_OBJ_ALIGN;
+        let hash = index ^ (index >> 7) ^ (index >> 17);
+        &self.shards[hash & (self.shards.len() - 1)]
+    }
+
+    /// Record that `from` has been moved to `to_obj`.
+    pub fn insert(&self, to_obj: ObjectReference, from: ObjectReference) {
+        let mut shard = self.shard_of(to_obj).lock().unwrap();
+        shard.insert(to_obj, from);
+    }
+
+    /// Get the old address of `object` if it has been moved in the current GC, or `object` itself
+    /// otherwise.
+    pub fn get(&self, object: ObjectReference) -> ObjectReference {
+        let shard = self.shard_of(object).lock().unwrap();
+        shard.get(&object).copied().unwrap_or(object)
+    }
+
+    /// Remove all entries.  Called after the VM has updated its tables.
+    pub fn clear(&self) {
+        let mut total = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard
+                .try_lock()
+                .expect("Should not have race during post_forwarding");
+            total += shard.len();
+            shard.clear();
+        }
+        let num_shards = self.shards.len();
+        debug!("Cleared backwarding table.  Entries: {total}, shards: {num_shards}");
+        probe!(mmtk_ruby, clear_backwarding_table, total, num_shards);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use mmtk::util::Address;
+
+    use super::*;
+
+    fn obj(addr: usize) -> ObjectReference {
+        ObjectReference::from_raw_address(Address::from_usize(addr)).unwrap()
+    }
+
+    #[test]
+    fn get_returns_old_address_until_cleared() {
+        let table = BackwardingTable::new(3);
+        assert_eq!(table.shards.len(), 4);
+
+        table.insert(obj(0x2000), obj(0x1000));
+        assert_eq!(table.get(obj(0x2000)), obj(0x1000));
+        assert_eq!(table.get(obj(0x3000)), obj(0x3000));
+
+        table.clear();
+        assert_eq!(table.get(obj(0x2000)), obj(0x2000));
+    }
+
+    /// Measure concurrent insertion with one shard and with the default number of shards.  Run it
+    /// with `cargo test --release -- --ignored --nocapture backwarding_table_scalability`.
+    #[test]
+    #[ignore]
+    fn backwarding_table_scalability() {
+        const INSERTS_PER_THREAD: usize = 1 << 20;
+        for threads in [1, 2, 4, 8, 16] {
+            for num_shards in [1, threads * 4] {
+                let table = BackwardingTable::new(num_shards);
+                let start = Instant::now();
+                std::thread::scope(|s| {
+                    for t in 0..threads {
+                        let table = &table;
+                        s.spawn(move || {
+                            // Each thread copies objects into its own region, like GC workers
+                            // copying into their own blocks.
+                            let base = 0x1000_0000_0000 + t * 0x1_0000_0000;
+                            for i in 0..INSERTS_PER_THREAD {
+                                let addr = base + i * MIN_OBJ_ALIGN * 8;
+                                table.insert(obj(addr), obj(addr + 0x8000_0000));
+                            }
+                        });
+                    }
+                });
+                let elapsed = start.elapsed();
+                let total = threads * INSERTS_PER_THREAD;
+                println!(
+                    "threads: {threads:2}, shards: {:2}, inserts: {total}, time: {elapsed:?}, {:.1} Minserts/s",
+                    table.shards.len(),
+                    total as f64 / elapsed.as_secs_f64() / 1e6
+                );
+            }
+        }
+    }
+}
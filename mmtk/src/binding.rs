@@ -1,6 +1,6 @@
-use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 use std::sync::Mutex;
 use std::thread::JoinHandle;
 
@@ -9,7 +9,15 @@ use mmtk::MMTK;
 
 use crate::abi;
 use crate::abi::RubyBindingOptions;
+use crate::backwarding::BackwardingTable;
+use crate::copy_failure::TryCopyFailureInjector;
+use crate::deferred_obj_free::DeferredObjFree;
+use crate::heap_verifier::HeapVerifier;
+use crate::histogram::ObjectHistogramCollector;
 use crate::ppp::PPPRegistry;
+use crate::stack_watermark::StackWatermarks;
+use crate::wb_check::WriteBarrierChecker;
+use crate::wb_unprotected::WbUnprotectedObjects;
 use crate::weak_proc::WeakProcessor;
 use crate::Ruby;
 
@@ -52,12 +60,29 @@ pub struct RubyBinding {
     pub ppp_registry: PPPRegistry,
     /// A "backwarding" table that can look up the old addresses of some moved objects,
     /// specifically objects using generic fields table.
-    pub(crate) backwarding_table: Mutex<HashMap<ObjectReference, ObjectReference>>,
+    pub(crate) backwarding_table: BackwardingTable,
     pub gc_thread_join_handles: Mutex<Vec<JoinHandle<()>>>,
-    pub wb_unprotected_objects: Mutex<HashSet<ObjectReference>>,
+    pub wb_unprotected_objects: WbUnprotectedObjects,
+    /// True if the next root scanning is a re-scanning, such as in the final pause of concurrent
+    /// marking.
+    pub(crate) roots_re_scanning: AtomicBool,
+    /// True between `stop_all_mutators` and `resume_mutators`.  Diagnostics check it to avoid
+    /// calling into the VM while the world is stopped.
+    pub(crate) world_stopped: AtomicBool,
+    pub stack_watermarks: StackWatermarks,
+    /// Names of imemo subtypes, indexed by the subtype.
+    pub(crate) imemo_type_names: Box<[CString]>,
+    pub(crate) type_descriptors: abi::TypeDescriptors,
+    pub(crate) class_names: abi::ClassNames,
+    pub object_histogram: ObjectHistogramCollector,
+    pub heap_verifier: HeapVerifier,
+    pub wb_checker: WriteBarrierChecker,
+    pub try_copy_failure: TryCopyFailureInjector,
+    pub deferred_obj_free: DeferredObjFree,
     pub st_entries_chunk_size: usize,
     pub st_bins_chunk_size: usize,
     pub concurrent_set_chunk_size: usize,
+    pub obj_free_chunk_size: usize,
 }
 
 unsafe impl Sync for RubyBinding {}
@@ -82,29 +107,84 @@ impl RubyBinding {
         unsafe {
             crate::BINDING_FAST_MUT.suffix_size = binding_options.suffix_size;
         }
+        debug_assert!(
+            binding_options
+                .weak_ref_referent_offset
+                .is_multiple_of(std::mem::size_of::<usize>()),
+            "weak_ref_referent_offset is not word-aligned: {}",
+            binding_options.weak_ref_referent_offset
+        );
 
         let st_entries_chunk_size = env_default::<usize>("RUBY_MMTK_ENTRIES_CHUNK_SIZE", 1024);
         let st_bins_chunk_size = env_default::<usize>("RUBY_MMTK_BINS_CHUNK_SIZE", 4096);
         let concurrent_set_chunk_size =
             env_default::<usize>("RUBY_MMTK_CONCURRENT_SET_CHUNK_SIZE", 1024);
+        let obj_free_chunk_size = env_default::<usize>("RUBY_MMTK_OBJ_FREE_CHUNK_SIZE", 4096);
+        let deferred_obj_free = env_default::<bool>("RUBY_MMTK_DEFERRED_OBJ_FREE", false);
+        let obj_free_buffer_size = env_default::<usize>("RUBY_MMTK_OBJ_FREE_BUFFER_SIZE", 128);
+        let soft_ref_clear_threshold =
+            env_default::<f64>("RUBY_MMTK_SOFT_REF_CLEAR_THRESHOLD", 0.9);
+        let backwarding_table_shards = env_default::<usize>(
+            "RUBY_MMTK_BACKWARDING_TABLE_SHARDS",
+            *mmtk.get_options().threads * 4,
+        );
+        let object_histogram_every_gc =
+            env_default::<bool>("RUBY_MMTK_OBJECT_HISTOGRAM_EVERY_GC", false);
+        let verify_heap = env_default::<bool>("RUBY_MMTK_VERIFY_HEAP", false);
+        let check_wb = env_default::<bool>("RUBY_MMTK_CHECK_WB", false);
+        let try_copy_failure_rate = env_default::<f64>("RUBY_MMTK_TRY_COPY_FAILURE_RATE", 0.0);
+        let try_copy_failure_seed = env_default::<u64>("RUBY_MMTK_TRY_COPY_FAILURE_SEED", 0);
 
         debug!("st_entries_chunk_size: {st_entries_chunk_size}");
         debug!("st_bins_chunk_size: {st_bins_chunk_size}");
         debug!("concurrent_set_chunk_size: {concurrent_set_chunk_size}");
+        debug!("obj_free_chunk_size: {obj_free_chunk_size}");
+        debug!("deferred_obj_free: {deferred_obj_free}");
+        debug!("obj_free_buffer_size: {obj_free_buffer_size}");
+        debug!("soft_ref_clear_threshold: {soft_ref_clear_threshold}");
+        debug!("backwarding_table_shards: {backwarding_table_shards}");
+        debug!("object_histogram_every_gc: {object_histogram_every_gc}");
+        debug!("verify_heap: {verify_heap}");
+        debug!("check_wb: {check_wb}");
+        debug!("try_copy_failure_rate: {try_copy_failure_rate}");
+        debug!("try_copy_failure_seed: {try_copy_failure_seed}");
+
+        let imemo_type_names = abi::get_imemo_type_names(unsafe { &*upcalls });
 
         Self {
             mmtk,
             options: binding_options.clone(),
             upcalls,
             plan_name: Mutex::new(None),
-            weak_proc: WeakProcessor::new(),
+            weak_proc: WeakProcessor::new(obj_free_buffer_size, soft_ref_clear_threshold),
             ppp_registry: PPPRegistry::new(),
-            backwarding_table: Default::default(),
+            backwarding_table: BackwardingTable::new(backwarding_table_shards),
             gc_thread_join_handles: Default::default(),
-            wb_unprotected_objects: Default::default(),
+            wb_unprotected_objects: WbUnprotectedObjects::new(
+                mmtk::memory_manager::starting_heap_address(),
+                mmtk::memory_manager::last_heap_address(),
+            ),
+            roots_re_scanning: AtomicBool::new(false),
+            world_stopped: AtomicBool::new(false),
+            stack_watermarks: Default::default(),
+            type_descriptors: abi::TypeDescriptors::new(&imemo_type_names),
+            imemo_type_names,
+            class_names: Default::default(),
+            object_histogram: ObjectHistogramCollector::new(
+                *mmtk.get_options().threads,
+                object_histogram_every_gc,
+            ),
+            heap_verifier: HeapVerifier::new(verify_heap, *mmtk.get_options().threads),
+            wb_checker: WriteBarrierChecker::new(check_wb, *mmtk.get_options().threads),
+            try_copy_failure: TryCopyFailureInjector::new(
+                try_copy_failure_rate,
+                try_copy_failure_seed,
+            ),
+            deferred_obj_free: DeferredObjFree::new(deferred_obj_free),
             st_entries_chunk_size,
             st_bins_chunk_size,
             concurrent_set_chunk_size,
+            obj_free_chunk_size,
         }
     }
 
@@ -141,12 +221,10 @@ impl RubyBinding {
 
     pub fn register_wb_unprotected_object(&self, object: ObjectReference) {
         debug!("Registering WB-unprotected object: {}", object);
-        let mut objects = self.wb_unprotected_objects.lock().unwrap();
-        objects.insert(object);
+        self.wb_unprotected_objects.insert(object);
     }
 
     pub fn is_object_wb_unprotected(&self, object: ObjectReference) -> bool {
-        let objects = self.wb_unprotected_objects.lock().unwrap();
-        objects.contains(&object)
+        self.wb_unprotected_objects.contains(object)
     }
 }
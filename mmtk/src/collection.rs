@@ -16,7 +16,16 @@ impl Collection<Ruby> for VMCollection {
         F: FnMut(&'static mut mmtk::Mutator<Ruby>),
     {
         (upcalls().stop_the_world)(tls);
+        crate::binding()
+            .world_stopped
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        crate::binding().wb_checker.schedule_check(tls);
+        crate::binding().object_histogram.on_gc_start();
+        crate::binding().try_copy_failure.on_gc_start();
+        crate::binding().weak_proc.flush_all_obj_free_buffers();
+        crate::binding().deferred_obj_free.on_gc_start();
         crate::binding().ppp_registry.pin_ppp_children(tls);
+        crate::binding().heap_verifier.schedule_verification(tls);
         (upcalls().get_mutators)(
             Self::notify_mutator_ready::<F>,
             &mut mutator_visitor as *mut F as *mut _,
@@ -25,6 +34,13 @@ impl Collection<Ruby> for VMCollection {
     }
 
     fn resume_mutators(tls: VMWorkerThread) {
+        crate::binding().object_histogram.on_gc_end();
+        crate::binding().try_copy_failure.on_gc_end();
+        crate::binding().deferred_obj_free.on_gc_end();
+        crate::binding().weak_proc.weak_refs.on_gc_end();
+        crate::binding()
+            .world_stopped
+            .store(false, std::sync::atomic::Ordering::SeqCst);
         (upcalls().resume_mutators)(tls);
     }
 
@@ -72,11 +88,8 @@ impl Collection<Ruby> for VMCollection {
     }
 
     fn post_forwarding(_tls: VMWorkerThread) {
-        let mut backwarding_table = binding()
-            .backwarding_table
-            .try_lock()
-            .expect("Should not have race during post_forwarding");
-        backwarding_table.clear();
+        binding().backwarding_table.clear();
+        binding().weak_proc.finish_forwarding();
     }
 }
 
@@ -0,0 +1,143 @@
+//! Deterministic failure injection for `VMObjectModel::try_copy`.
+//!
+//! Immix defrag calls `try_copy`, and marks the object in place if copying fails.  That path is
+//! rarely exercised because the copy reserve is usually large enough.  With
+//! `RUBY_MMTK_TRY_COPY_FAILURE_RATE` set to a number between 0 and 1, that fraction of `try_copy`
+//! calls fail as if `alloc_copy` returned zero.  Whether a call fails is a function of
+//! `RUBY_MMTK_TRY_COPY_FAILURE_SEED`, the object address and the GC epoch, so a failing run can be
+//! reproduced.
+//!
+//! After each GC, we check that every object whose copying failed has been kept alive in place.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use mmtk::util::ObjectReference;
+
+/// Cumulative statistics of `try_copy` failures.
+#[repr(C)]
+#[derive(Default)]
+pub struct TryCopyFailureStats {
+    /// Failures injected by the binding.
+    pub injected: usize,
+    /// Failures because `alloc_copy` actually failed.
+    pub real: usize,
+    /// Objects that failed to be copied, and were kept alive in place.
+    pub recovered: usize,
+}
+
+pub struct TryCopyFailureInjector {
+    /// Fail a `try_copy` call if its hash is below this threshold.
+    threshold: u64,
+    seed: u64,
+    /// Incremented at the start of each GC so that the same object doesn't always fail.
+    gc_epoch: AtomicUsize,
+    injected: AtomicUsize,
+    real: AtomicUsize,
+    recovered: AtomicUsize,
+    /// Objects that failed to be copied in the current GC.
+    failed_objects: Mutex<Vec<ObjectReference>>,
+}
+
+impl TryCopyFailureInjector {
+    pub fn new(rate: f64, seed: u64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "The try_copy failure rate must be between 0 and 1: {rate}"
+        );
+        Self {
+            threshold: (rate * u64::MAX as f64) as u64,
+            seed,
+            gc_epoch: AtomicUsize::new(0),
+            injected: AtomicUsize::new(0),
+            real: AtomicUsize::new(0),
+            recovered: AtomicUsize::new(0),
+            failed_objects: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold != 0
+    }
+
+    /// The SplitMix64 finalizer.  Good enough for spreading addresses evenly.
+    fn mix(mut x: u64) -> u64 {
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^ (x >> 31)
+    }
+
+    /// Return true if copying `object` should fail in this GC.
+    pub fn should_fail(&self, object: ObjectReference) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let epoch = self.gc_epoch.load(Ordering::Relaxed) as u64;
+        let hash =
+            Self::mix(self.seed ^ Self::mix(object.to_raw_address().as_usize() as u64 ^ epoch));
+        if hash >= self.threshold {
+            return false;
+        }
+        trace!("Injecting try_copy failure for {object}");
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        self.record_failed_object(object);
+        true
+    }
+
+    /// Called when `alloc_copy` actually failed.
+    pub fn on_real_failure(&self, object: ObjectReference) {
+        self.real.fetch_add(1, Ordering::Relaxed);
+        if self.is_enabled() {
+            self.record_failed_object(object);
+        }
+    }
+
+    fn record_failed_object(&self, object: ObjectReference) {
+        self.failed_objects.lock().unwrap().push(object);
+    }
+
+    pub fn on_gc_start(&self) {
+        if self.is_enabled() {
+            self.gc_epoch.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Called after the GC has finished tracing.  Check that the objects that failed to be
+    /// copied are kept alive at their original addresses.
+    pub fn on_gc_end(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let failed_objects = std::mem::take(
+            &mut *self
+                .failed_objects
+                .try_lock()
+                .expect("Should not have race after tracing"),
+        );
+        for &object in failed_objects.iter() {
+            assert!(
+                object.is_live() && object.get_forwarded_object().is_none(),
+                "Object {object} failed to be copied, but is not kept alive in place."
+            );
+        }
+        self.recovered
+            .fetch_add(failed_objects.len(), Ordering::Relaxed);
+
+        debug!(
+            "try_copy failures in this GC: {}.  Total: injected: {}, real: {}, recovered: {}",
+            failed_objects.len(),
+            self.injected.load(Ordering::Relaxed),
+            self.real.load(Ordering::Relaxed),
+            self.recovered.load(Ordering::Relaxed),
+        );
+    }
+
+    pub fn stats(&self) -> TryCopyFailureStats {
+        TryCopyFailureStats {
+            injected: self.injected.load(Ordering::Relaxed),
+            real: self.real.load(Ordering::Relaxed),
+            recovered: self.recovered.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Call `obj_free` on a background thread after mutators resume.
+//!
+//! When enabled with the `RUBY_MMTK_DEFERRED_OBJ_FREE` environment variable, GC workers don't call
+//! `obj_free` for some dead candidates.  Instead, they keep those candidates alive for one more GC,
+//! and add them to a backlog.  Weak references to those objects are cleared before they are
+//! retained, as if they were freed (see `WeakProcessor::process_weak_stuff`).  A dedicated
+//! finalizer thread calls `obj_free` on the backlog while mutators are running.  Once `obj_free`
+//! is called, an object is no longer retained, and its memory is reclaimed by the next GC.
+//!
+//! Calling `obj_free` while mutators are running is only safe if it does not touch state that
+//! mutators may be using at the same time without synchronization, such as the VM's global
+//! tables, and if it does not allocate objects or call into the Ruby interpreter.  That depends on
+//! the type of the object, and only the VM knows it.  Therefore only candidates whose builtin types
+//! the VM declared with `mmtk_set_obj_free_deferrable_types` are deferred.  By default, no type is
+//! deferrable, and all dead candidates are freed by GC workers while the world is stopped.
+//!
+//! The finalizer thread does not run during GC.  GC waits for the current batch to finish when
+//! the world stops, and keeps the remaining backlog alive.
+//!
+//! The finalizer thread is registered to the VM as a GC thread of the kind
+//! `GC_THREAD_KIND_FINALIZER`.  The VM must accept that kind in `init_gc_worker_thread`.  This kind
+//! is not known by the Ruby revision pinned in `Cargo.toml` (see README.md).
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use mmtk::util::ObjectReference;
+
+use crate::abi::{GCThreadTLS, RubyObjectAccess};
+use crate::upcalls;
+
+/// The maximum number of objects the finalizer thread processes without checking for GC.
+const BATCH_SIZE: usize = 256;
+
+/// Statistics of deferred `obj_free`.
+#[repr(C)]
+pub struct DeferredObjFreeStats {
+    /// The number of objects waiting for `obj_free`.
+    pub backlog: usize,
+    /// The maximum length of the backlog seen so far.
+    pub max_backlog: usize,
+    /// The total number of objects deferred.
+    pub deferred: usize,
+    /// The total number of objects freed by the finalizer thread.
+    pub freed: usize,
+}
+
+#[derive(Default)]
+struct State {
+    /// Objects waiting for `obj_free`.
+    backlog: Vec<ObjectReference>,
+    /// True from stopping mutators to resuming mutators.
+    gc_in_progress: bool,
+    /// True if the finalizer thread is calling `obj_free`.
+    busy: bool,
+    /// Ask the finalizer thread to quit.
+    shutdown: bool,
+}
+
+pub struct DeferredObjFree {
+    enabled: bool,
+    /// A bit mask of builtin types (`1 << T_xxx`) whose `obj_free` can be called on the finalizer
+    /// thread while mutators are running.
+    deferrable_types: AtomicU32,
+    state: Mutex<State>,
+    cond: Condvar,
+    /// Dead objects found in the current GC.  GC workers append to it in parallel.
+    pending: Mutex<Vec<ObjectReference>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    max_backlog: AtomicUsize,
+    deferred: AtomicUsize,
+    freed: AtomicUsize,
+}
+
+impl DeferredObjFree {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            deferrable_types: AtomicU32::new(0),
+            state: Default::default(),
+            cond: Condvar::new(),
+            pending: Default::default(),
+            thread: Default::default(),
+            max_backlog: AtomicUsize::new(0),
+            deferred: AtomicUsize::new(0),
+            freed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Set the builtin types whose `obj_free` can be deferred.  Bit `T_xxx` of `mask` represents the
+    /// type `T_xxx`.
+    pub fn set_deferrable_types(&self, mask: u32) {
+        debug!("obj_free deferrable types: {mask:#x}");
+        self.deferrable_types.store(mask, Ordering::SeqCst);
+    }
+
+    /// Return true if the `obj_free` of `acc` can be called on the finalizer thread.
+    pub fn is_deferrable(&self, acc: RubyObjectAccess) -> bool {
+        let mask = self.deferrable_types.load(Ordering::Relaxed);
+        mask & (1 << acc.builtin_type()) != 0
+    }
+
+    /// Called when the world is stopped.  Wait for the finalizer thread to finish its current
+    /// batch.
+    pub fn on_gc_start(&self) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.gc_in_progress = true;
+        while state.busy {
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    /// Add dead objects found by a GC worker.  They are retained by `retain` later in this GC.
+    pub fn add_pending(&self, objects: &[ObjectReference]) {
+        self.deferred.fetch_add(objects.len(), Ordering::Relaxed);
+        self.pending.lock().unwrap().extend_from_slice(objects);
+    }
+
+    /// Return true if any dead objects have been found in the current GC.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Return true if any objects are waiting for the finalizer thread.
+    pub fn has_backlog(&self) -> bool {
+        !self.state.lock().unwrap().backlog.is_empty()
+    }
+
+    /// Keep the objects in the backlog and the objects found in this GC alive.  `trace` traces an
+    /// object and returns its new address.
+    pub fn retain(&self, mut trace: impl FnMut(ObjectReference) -> ObjectReference) {
+        let mut state = self.state.lock().unwrap();
+        debug_assert!(state.gc_in_progress && !state.busy);
+        for object in state.backlog.iter_mut() {
+            *object = trace(*object);
+        }
+        for object in self.pending.lock().unwrap().iter_mut() {
+            *object = trace(*object);
+        }
+    }
+
+    /// Update the addresses of the objects in the backlog and the objects found in this GC.
+    /// Used by MarkCompact, which computes new addresses after weak reference processing.
+    pub fn forward(&self, forward: impl Fn(ObjectReference) -> ObjectReference) {
+        let mut state = self.state.lock().unwrap();
+        debug_assert!(state.gc_in_progress && !state.busy);
+        for object in state.backlog.iter_mut() {
+            *object = forward(*object);
+        }
+        for object in self.pending.lock().unwrap().iter_mut() {
+            *object = forward(*object);
+        }
+    }
+
+    /// Called before resuming mutators.  Move the objects found in this GC to the backlog, and
+    /// let the finalizer thread continue.
+    pub fn on_gc_end(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        {
+            let mut state = self.state.lock().unwrap();
+            state.backlog.extend(pending);
+            state.gc_in_progress = false;
+            let backlog = state.backlog.len();
+            self.max_backlog.fetch_max(backlog, Ordering::Relaxed);
+            debug!("Deferred obj_free backlog: {backlog}");
+        }
+        self.cond.notify_all();
+        self.ensure_thread_started();
+    }
+
+    fn ensure_thread_started(&self) {
+        let mut thread = self.thread.lock().unwrap();
+        if thread.is_some() {
+            return;
+        }
+
+        let join_handle = thread::Builder::new()
+            .name("MMTk Finalizer Thread".to_string())
+            .spawn(|| {
+                debug!("Hello! This is MMTk Finalizer Thread running!");
+                crate::register_gc_thread(thread::current().id());
+                let gc_thread_tls = Box::into_raw(Box::new(GCThreadTLS::for_finalizer()));
+                (upcalls().init_gc_worker_thread)(gc_thread_tls);
+                crate::binding().deferred_obj_free.run_finalizer_thread();
+                crate::unregister_gc_thread(thread::current().id());
+                debug!("MMTk Finalizer Thread is quitting. Good bye!");
+            })
+            .unwrap();
+        *thread = Some(join_handle);
+    }
+
+    fn run_finalizer_thread(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                return;
+            }
+            if state.gc_in_progress || state.backlog.is_empty() {
+                state = self.cond.wait(state).unwrap();
+                continue;
+            }
+
+            let batch_start = state.backlog.len().saturating_sub(BATCH_SIZE);
+            let batch = state.backlog.split_off(batch_start);
+            state.busy = true;
+            drop(state);
+
+            for object in batch.iter().copied() {
+                (upcalls().call_obj_free)(object);
+            }
+            self.freed.fetch_add(batch.len(), Ordering::Relaxed);
+
+            state = self.state.lock().unwrap();
+            state.busy = false;
+            self.cond.notify_all();
+        }
+    }
+
+    /// Stop the finalizer thread, if running, and wait for it to quit.  The backlog is kept, and
+    /// the thread will be restarted after the next GC.  Called before forking.
+    pub fn stop_thread(&self) {
+        let Some(join_handle) = self.thread.lock().unwrap().take() else {
+            return;
+        };
+
+        self.state.lock().unwrap().shutdown = true;
+        self.cond.notify_all();
+        join_handle.join().unwrap();
+        self.state.lock().unwrap().shutdown = false;
+    }
+
+    /// Take all objects in the backlog after the finalizer thread finishes its current batch.
+    /// Called when the VM is exiting, and needs to call `obj_free` on all remaining objects.  The
+    /// finalizer thread, if running, keeps running, and will process objects deferred by later
+    /// GCs.
+    pub fn take_backlog(&self) -> Vec<ObjectReference> {
+        let mut state = self.state.lock().unwrap();
+        while state.busy {
+            state = self.cond.wait(state).unwrap();
+        }
+        let mut backlog = std::mem::take(&mut state.backlog);
+        backlog.append(&mut self.pending.lock().unwrap());
+        backlog
+    }
+
+    pub fn stats(&self) -> DeferredObjFreeStats {
+        DeferredObjFreeStats {
+            backlog: self.state.lock().unwrap().backlog.len(),
+            max_backlog: self.max_backlog.load(Ordering::Relaxed),
+            deferred: self.deferred.load(Ordering::Relaxed),
+            freed: self.freed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mmtk::util::Address;
+
+    use super::*;
+
+    fn obj(addr: usize) -> ObjectReference {
+        ObjectReference::from_raw_address(Address::from_usize(addr)).unwrap()
+    }
+
+    #[test]
+    fn forward_updates_backlog_and_pending() {
+        let deferred_obj_free = DeferredObjFree::new(true);
+        deferred_obj_free.on_gc_start();
+        deferred_obj_free
+            .state
+            .lock()
+            .unwrap()
+            .backlog
+            .push(obj(0x1000));
+        deferred_obj_free.add_pending(&[obj(0x2000)]);
+
+        deferred_obj_free.forward(|object| obj(object.to_raw_address().as_usize() + 0x100));
+
+        assert_eq!(
+            deferred_obj_free.take_backlog(),
+            vec![obj(0x1100), obj(0x2100)]
+        );
+    }
+}
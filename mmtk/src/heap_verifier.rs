@@ -0,0 +1,387 @@
+//! An opt-in verifier that checks the whole heap after GC.
+//!
+//! When enabled with the `RUBY_MMTK_VERIFY_HEAP` environment variable, it runs in the `Final`
+//! stage, i.e. after the release phase, using all GC workers.  It enumerates all objects, scans
+//! each of them with `scan_object_ruby_style` in parallel work packets, and checks that
+//!
+//! -   the hidden header of every object is sane, and
+//! -   every reference points to an MMTk object (i.e. it has its VO bit set, so it is not in a freed
+//!     line or cell), and the object is not forwarded.
+//!
+//! While enabled, the binding records the roots and the edges traced by each GC worker in buffers
+//! owned by that worker, so that workers don't contend on a shared table.  When the first bad
+//! reference is found, the verifier merges the buffers and finds the shortest path from a root to
+//! each object, and then it logs the path from a root to the source object of each bad reference.
+//!
+//! It only runs after full-heap GCs.  After a nursery GC, old objects that are already dead still
+//! have their VO bits set, and they may point to young objects that have been freed.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::ObjectReference;
+use mmtk::util::VMWorkerThread;
+use mmtk::vm::ObjectModel;
+
+use crate::abi::{GCThreadTLS, RubyObjectAccess};
+use crate::object_model::VMObjectModel;
+use crate::utils::AfterAll;
+use crate::{is_mmtk_object_safe, upcalls, Ruby};
+
+/// The maximum number of errors reported in detail.
+const MAX_REPORTED_ERRORS: usize = 16;
+
+/// The number of objects verified in one work packet.
+const VERIFY_CHUNK_SIZE: usize = 4096;
+
+/// The maximum number of objects printed in a path from a root.
+const MAX_PATH_LENGTH: usize = 64;
+
+/// Roots and edges recorded by one GC worker.  Only the worker itself adds to its buffer, so the
+/// lock is not contended until the buffers are taken in `VerifyHeap`.
+#[derive(Default)]
+struct WorkerRecords {
+    roots: Vec<(ObjectReference, &'static str)>,
+    edges: Vec<(ObjectReference, ObjectReference)>,
+}
+
+pub struct HeapVerifier {
+    enabled: bool,
+    per_worker: Vec<Mutex<WorkerRecords>>,
+}
+
+impl HeapVerifier {
+    pub fn new(enabled: bool, num_workers: usize) -> Self {
+        Self {
+            enabled,
+            per_worker: (0..num_workers).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Record that `objects` are roots found by `root_scan_kind` on the worker `ordinal`.
+    pub fn record_roots(
+        &self,
+        ordinal: usize,
+        objects: &[ObjectReference],
+        root_scan_kind: &'static str,
+    ) {
+        let mut records = self.per_worker[ordinal].lock().unwrap();
+        records
+            .roots
+            .extend(objects.iter().map(|object| (*object, root_scan_kind)));
+    }
+
+    /// Record the edge `parent -> child` traced on the worker `ordinal`.
+    pub fn record_edge(&self, ordinal: usize, parent: ObjectReference, child: ObjectReference) {
+        let mut records = self.per_worker[ordinal].lock().unwrap();
+        records.edges.push((parent, child));
+    }
+
+    /// Schedule the verification in the `Final` stage if enabled.  Called when the world stops.
+    pub fn schedule_verification(&self, tls: VMWorkerThread) {
+        if !self.enabled {
+            return;
+        }
+        self.take_records();
+
+        let gc_tls = unsafe { GCThreadTLS::from_vwt_check(tls) };
+        gc_tls.worker().scheduler().work_buckets[WorkBucketStage::Final].add(VerifyHeap);
+    }
+
+    /// Take the records of all workers, leaving the buffers empty.
+    fn take_records(&self) -> Vec<WorkerRecords> {
+        self.per_worker
+            .iter()
+            .map(|records| std::mem::take(&mut *records.lock().unwrap()))
+            .collect()
+    }
+
+    fn check_target(target_object: ObjectReference) -> Option<&'static str> {
+        if !is_mmtk_object_safe(target_object.to_raw_address()) {
+            return Some("target is not an MMTk object, or has been freed");
+        }
+        if target_object.get_forwarded_object().is_some() {
+            return Some("target is forwarded");
+        }
+        if !RubyObjectAccess::from_objref(target_object).is_hidden_header_sane() {
+            return Some("target has a corrupted hidden header");
+        }
+        None
+    }
+}
+
+/// The roots of the current GC, and the parent of each object on a shortest path from a root.
+struct PathsFromRoots {
+    /// Roots, and the kind of root scanning that found each of them.
+    roots: HashMap<ObjectReference, &'static str>,
+    parents: HashMap<ObjectReference, ObjectReference>,
+}
+
+impl PathsFromRoots {
+    /// Merge the records of all workers, and find the parents with a breadth-first search from
+    /// the roots.
+    fn from_records(records: Vec<WorkerRecords>) -> Self {
+        let mut roots = HashMap::new();
+        let mut children: HashMap<ObjectReference, Vec<ObjectReference>> = HashMap::new();
+        for records in records {
+            for (object, root_scan_kind) in records.roots {
+                roots.entry(object).or_insert(root_scan_kind);
+            }
+            for (parent, child) in records.edges {
+                children.entry(parent).or_default().push(child);
+            }
+        }
+
+        let mut parents = HashMap::new();
+        let mut queue: VecDeque<ObjectReference> = roots.keys().copied().collect();
+        while let Some(object) = queue.pop_front() {
+            for child in children.remove(&object).unwrap_or_default() {
+                if !roots.contains_key(&child) && !parents.contains_key(&child) {
+                    parents.insert(child, object);
+                    queue.push_back(child);
+                }
+            }
+        }
+
+        Self { roots, parents }
+    }
+
+    /// Log the path from a root to `object`.
+    fn log_path_from_root(&self, object: ObjectReference) {
+        let path = path_to_root(object, |o| self.parents.get(&o).copied(), MAX_PATH_LENGTH);
+
+        error!("Path from root:");
+        let first = *path.last().unwrap();
+        match self.roots.get(&first) {
+            Some(root_scan_kind) => error!("  (root from {root_scan_kind})"),
+            None if path.len() == MAX_PATH_LENGTH => error!("  ..."),
+            None => error!("  (not reached from any recorded root)"),
+        }
+        for o in path.iter().rev() {
+            error!(
+                "  {o} {}",
+                RubyObjectAccess::from_objref(*o)
+                    .type_name()
+                    .to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Follow `parent_of` from `object` until reaching an object without a parent, and return the
+/// objects on the way, starting with `object`.  At most `max_len` objects are returned, which
+/// also stops cycles.
+fn path_to_root(
+    object: ObjectReference,
+    parent_of: impl Fn(ObjectReference) -> Option<ObjectReference>,
+    max_len: usize,
+) -> Vec<ObjectReference> {
+    let mut path = vec![object];
+    while path.len() < max_len {
+        match parent_of(*path.last().unwrap()) {
+            Some(parent) => path.push(parent),
+            None => break,
+        }
+    }
+    path
+}
+
+/// States shared by the work packets that verify the heap in one GC.
+struct Verification {
+    num_objects: usize,
+    num_edges: AtomicUsize,
+    num_errors: AtomicUsize,
+    /// The records taken from the workers.  They are only merged if an error is reported.
+    records: Mutex<Vec<WorkerRecords>>,
+    paths: OnceLock<PathsFromRoots>,
+}
+
+impl Verification {
+    /// Count an error, and return true if it should be reported in detail.
+    fn add_error(&self) -> bool {
+        self.num_errors.fetch_add(1, Ordering::Relaxed) < MAX_REPORTED_ERRORS
+    }
+
+    fn paths(&self) -> &PathsFromRoots {
+        self.paths.get_or_init(|| {
+            PathsFromRoots::from_records(std::mem::take(&mut *self.records.lock().unwrap()))
+        })
+    }
+}
+
+/// Enumerate all objects, and verify them in parallel.
+struct VerifyHeap;
+
+impl GCWork<Ruby> for VerifyHeap {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let is_nursery_gc = (crate::mmtk().get_plan().generational())
+            .is_some_and(|gen| gen.is_current_gc_nursery());
+        let records = crate::binding().heap_verifier.take_records();
+        if is_nursery_gc {
+            return;
+        }
+
+        let mut objects = vec![];
+        crate::mmtk().enumerate_objects(|object| objects.push(object));
+
+        let verification = Arc::new(Verification {
+            num_objects: objects.len(),
+            num_edges: AtomicUsize::new(0),
+            num_errors: AtomicUsize::new(0),
+            records: Mutex::new(records),
+            paths: OnceLock::new(),
+        });
+
+        let after_all = Arc::new(AfterAll::new(WorkBucketStage::Final));
+        after_all.add_packets(vec![Box::new(ReportHeapVerification {
+            verification: verification.clone(),
+        })]);
+
+        let packets = objects
+            .chunks(VERIFY_CHUNK_SIZE)
+            .map(|chunk| {
+                Box::new(VerifyObjects {
+                    objects: chunk.to_vec(),
+                    verification: verification.clone(),
+                    after_all: after_all.clone(),
+                }) as _
+            })
+            .collect::<Vec<_>>();
+
+        // Count one more so that the report is scheduled even if there are no objects.
+        after_all.count_up(packets.len() + 1);
+        worker.scheduler().work_buckets[WorkBucketStage::Final].bulk_add(packets);
+        after_all.count_down(worker);
+    }
+}
+
+struct VerifyObjects {
+    objects: Vec<ObjectReference>,
+    verification: Arc<Verification>,
+    after_all: Arc<AfterAll>,
+}
+
+impl GCWork<Ruby> for VerifyObjects {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let gc_tls = unsafe { GCThreadTLS::from_vwt_check(worker.tls) };
+        let verification = &self.verification;
+        let mut num_edges = 0usize;
+
+        for object in self.objects.iter().copied() {
+            let acc = RubyObjectAccess::from_objref(object);
+            if !acc.is_hidden_header_sane() {
+                if verification.add_error() {
+                    error!("[heap verifier] Object {object} has a corrupted hidden header.");
+                    VMObjectModel::dump_object(object);
+                    verification.paths().log_path_from_root(object);
+                }
+                // Don't scan it.  We can't even trust its size.
+                continue;
+            }
+
+            let visit_object = |_worker, target_object: ObjectReference, _pin| {
+                num_edges += 1;
+                if let Some(problem) = HeapVerifier::check_target(target_object) {
+                    if verification.add_error() {
+                        error!(
+                            "[heap verifier] Bad reference: {object} -> {target_object}: {problem}"
+                        );
+                        error!("Source:");
+                        VMObjectModel::dump_object(object);
+                        if is_mmtk_object_safe(target_object.to_raw_address()) {
+                            error!("Target:");
+                            VMObjectModel::dump_object(target_object);
+                        }
+                        verification.paths().log_path_from_root(object);
+                    }
+                }
+                target_object
+            };
+            gc_tls
+                .object_closure
+                .set_temporarily_and_run_code(visit_object, || {
+                    (upcalls().scan_object_ruby_style)(object);
+                });
+        }
+
+        verification
+            .num_edges
+            .fetch_add(num_edges, Ordering::Relaxed);
+        self.after_all.count_down(worker);
+    }
+}
+
+/// Report the result after all objects are verified.  If any error is found, log a summary and
+/// panic, so that soak tests stop at the first GC that leaves the heap broken.  The details of
+/// the first `MAX_REPORTED_ERRORS` errors have been logged by `VerifyObjects`.
+struct ReportHeapVerification {
+    verification: Arc<Verification>,
+}
+
+impl GCWork<Ruby> for ReportHeapVerification {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let num_objects = self.verification.num_objects;
+        let num_edges = self.verification.num_edges.load(Ordering::Relaxed);
+        let num_errors = self.verification.num_errors.load(Ordering::Relaxed);
+        debug!("[heap verifier] Objects: {num_objects}, edges: {num_edges}, errors: {num_errors}");
+        if num_errors != 0 {
+            let plan = *crate::mmtk().get_options().plan;
+            error!(
+                "[heap verifier] Found {num_errors} errors in {num_objects} objects and {num_edges} edges after a full-heap {plan:?} GC.  The first {} errors are logged above.",
+                num_errors.min(MAX_REPORTED_ERRORS)
+            );
+            panic!("[heap verifier] Heap verification failed with {num_errors} errors.  See the log for details.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mmtk::util::Address;
+
+    use super::*;
+
+    fn obj(addr: usize) -> ObjectReference {
+        ObjectReference::from_raw_address(Address::from_usize(addr)).unwrap()
+    }
+
+    #[test]
+    fn path_ends_at_root() {
+        let parents = HashMap::from([(obj(0x3000), obj(0x2000)), (obj(0x2000), obj(0x1000))]);
+        let path = path_to_root(obj(0x3000), |o| parents.get(&o).copied(), 64);
+        assert_eq!(path, vec![obj(0x3000), obj(0x2000), obj(0x1000)]);
+    }
+
+    #[test]
+    fn path_is_bounded_on_cycles() {
+        let parents = HashMap::from([(obj(0x1000), obj(0x2000)), (obj(0x2000), obj(0x1000))]);
+        let path = path_to_root(obj(0x1000), |o| parents.get(&o).copied(), 5);
+        assert_eq!(path.len(), 5);
+    }
+
+    #[test]
+    fn paths_are_merged_from_all_workers() {
+        // Worker 0 reached 0x3000 from 0x2000 before worker 1 recorded 0x2000 from the root.  The
+        // cycle 0x3000 -> 0x2000 must not hide the path from the root.
+        let records = vec![
+            WorkerRecords {
+                roots: vec![],
+                edges: vec![(obj(0x3000), obj(0x2000)), (obj(0x2000), obj(0x3000))],
+            },
+            WorkerRecords {
+                roots: vec![(obj(0x1000), "test_roots")],
+                edges: vec![(obj(0x1000), obj(0x2000))],
+            },
+        ];
+        let paths = PathsFromRoots::from_records(records);
+        let path = path_to_root(obj(0x3000), |o| paths.parents.get(&o).copied(), 64);
+        assert_eq!(path, vec![obj(0x3000), obj(0x2000), obj(0x1000)]);
+        assert_eq!(paths.roots.get(&obj(0x1000)), Some(&"test_roots"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Per-type object histogram collected by GC workers.
+//!
+//! In a full-heap tracing GC, each live object is scanned exactly once (see
+//! `VMScanning::UNIQUE_OBJECT_ENQUEUING`).  When requested, the workers count objects and bytes
+//! per `T_*` builtin type while scanning, so that `ObjectSpace.count_objects` and
+//! `count_objects_size` don't need to walk the heap on the mutator.
+//!
+//! Nursery GCs only scan young objects, so histograms are only collected in full-heap GCs.
+//!
+//! The histogram only counts objects that survive the GC.  This differs from
+//! `ObjectSpace.count_objects` with CRuby's default GC, which counts every allocated slot,
+//! including garbage not swept yet, and reports free slots as `T_NONE`.  Here `T_NONE` is always
+//! zero.  The VM should document this if it exposes the histogram through `count_objects`.
+//!
+//! An object may be scanned more than once only if the plan traces the heap twice, as MarkCompact
+//! does.  `mmtk_init_binding` rejects MarkCompact, so counting every scan is exact.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use mmtk::util::ObjectReference;
+
+use crate::abi::{GCThreadTLS, RubyObjectAccess, RUBY_T_MASK};
+
+/// The number of `T_*` builtin types.
+pub const HISTOGRAM_NUM_TYPES: usize = RUBY_T_MASK + 1;
+
+/// Object counts and bytes per `T_*` builtin type, indexed by the builtin type.
+#[repr(C)]
+#[derive(Clone, Default)]
+pub struct ObjectHistogram {
+    pub counts: [usize; HISTOGRAM_NUM_TYPES],
+    pub bytes: [usize; HISTOGRAM_NUM_TYPES],
+}
+
+/// The counters of one GC worker.  Each worker only updates its own counters so that the workers
+/// don't contend on the same cache lines.
+#[derive(Default)]
+struct WorkerCounters {
+    counts: [AtomicUsize; HISTOGRAM_NUM_TYPES],
+    bytes: [AtomicUsize; HISTOGRAM_NUM_TYPES],
+}
+
+impl WorkerCounters {
+    fn reset(&self) {
+        for i in 0..HISTOGRAM_NUM_TYPES {
+            self.counts[i].store(0, Ordering::Relaxed);
+            self.bytes[i].store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn add_to(&self, histogram: &mut ObjectHistogram) {
+        for i in 0..HISTOGRAM_NUM_TYPES {
+            histogram.counts[i] += self.counts[i].load(Ordering::Relaxed);
+            histogram.bytes[i] += self.bytes[i].load(Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct ObjectHistogramCollector {
+    /// Collect a histogram after every full-heap GC.
+    every_gc: bool,
+    /// The VM requested a histogram in the next full-heap GC.
+    requested: AtomicBool,
+    /// True if a histogram is collected in the current GC.
+    active: AtomicBool,
+    per_worker: Vec<WorkerCounters>,
+    /// The histogram of the last full-heap GC that collected one.
+    last: Mutex<Option<ObjectHistogram>>,
+}
+
+impl ObjectHistogramCollector {
+    pub fn new(num_workers: usize, every_gc: bool) -> Self {
+        Self {
+            every_gc,
+            requested: AtomicBool::new(false),
+            active: AtomicBool::new(false),
+            per_worker: (0..num_workers).map(|_| Default::default()).collect(),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Ask for a histogram to be collected in the next full-heap GC.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Called when the world is stopped.  Start counting if this GC is a full-heap GC and a
+    /// histogram is wanted.
+    pub fn on_gc_start(&self) {
+        let is_full_heap_gc = !(crate::mmtk().get_plan().generational())
+            .is_some_and(|gen| gen.is_current_gc_nursery());
+        if !is_full_heap_gc || !(self.every_gc || self.requested.load(Ordering::SeqCst)) {
+            return;
+        }
+
+        debug!("Collecting object histogram in this GC.");
+        for counters in self.per_worker.iter() {
+            counters.reset();
+        }
+        self.active.store(true, Ordering::SeqCst);
+    }
+
+    /// Called when a worker scans `object`.
+    #[inline(always)]
+    pub fn record(&self, gc_tls: &mut GCThreadTLS, object: ObjectReference) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let acc = RubyObjectAccess::from_objref(object);
+        let builtin_type = acc.builtin_type();
+        let counters = &self.per_worker[gc_tls.worker().ordinal];
+        counters.counts[builtin_type].fetch_add(1, Ordering::Relaxed);
+        counters.bytes[builtin_type].fetch_add(acc.object_size(), Ordering::Relaxed);
+    }
+
+    /// Called before resuming mutators.  Publish the histogram if one was collected in this GC.
+    pub fn on_gc_end(&self) {
+        if !self.active.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let mut histogram = ObjectHistogram::default();
+        for counters in self.per_worker.iter() {
+            counters.add_to(&mut histogram);
+        }
+        debug!(
+            "Object histogram collected.  Objects: {}, bytes: {}",
+            histogram.counts.iter().sum::<usize>(),
+            histogram.bytes.iter().sum::<usize>(),
+        );
+
+        *self.last.lock().unwrap() = Some(histogram);
+        self.requested.store(false, Ordering::SeqCst);
+    }
+
+    /// Get the histogram of the last full-heap GC that collected one.
+    pub fn last_histogram(&self) -> Option<ObjectHistogram> {
+        self.last.lock().unwrap().clone()
+    }
+}
@@ -15,7 +15,7 @@ use std::thread::ThreadId;
 use abi::RubyUpcalls;
 use binding::{RubyBinding, RubyBindingFast, RubyBindingFastMut};
 use mmtk::util::Address;
-use mmtk::vm::slot::{SimpleSlot, UnimplementedMemorySlice};
+use mmtk::vm::slot::SimpleSlot;
 use mmtk::vm::VMBinding;
 use mmtk::MMTK;
 use once_cell::sync::OnceCell;
@@ -23,13 +23,23 @@ use once_cell::sync::OnceCell;
 pub mod abi;
 pub mod active_plan;
 pub mod api;
+pub mod backwarding;
 pub mod binding;
 pub mod collection;
+pub mod copy_failure;
+pub mod deferred_obj_free;
+pub mod heap_verifier;
+pub mod histogram;
+pub mod log_bit;
 pub mod object_model;
 pub mod ppp;
 pub mod reference_glue;
 pub mod scanning;
+pub mod slot;
+pub mod stack_watermark;
 pub mod utils;
+pub mod wb_check;
+pub mod wb_unprotected;
 pub mod weak_proc;
 pub mod yjit_support;
 
@@ -43,8 +53,8 @@ pub type RubySlot = SimpleSlot;
 
 /// Ruby memory slice, i.e. an array of VALUEs.
 /// It is used by array-copy barriers which is supposed to perform bettern than copying array
-/// elements one by one.  At this moment, we just leave it unimplemented.
-pub type RubyMemorySlice = UnimplementedMemorySlice<RubySlot>;
+/// elements one by one.
+pub use slot::RubyMemorySlice;
 
 impl VMBinding for Ruby {
     type VMObjectModel = object_model::VMObjectModel;
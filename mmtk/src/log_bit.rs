@@ -0,0 +1,129 @@
+//! Helpers for inlining the fast path of write barriers in C and in YJIT-generated code.
+//!
+//! The fast path loads the unlog bit of an object (or a field) from side metadata, and only calls
+//! into Rust if the bit is set.  This module describes where the bits are, and checks that the
+//! inlined computation agrees with mmtk-core.
+
+use std::sync::atomic::Ordering;
+
+use mmtk::util::constants::BITS_IN_BYTE;
+use mmtk::util::metadata::side_metadata::SideMetadataSpec;
+use mmtk::util::{Address, ObjectReference};
+use mmtk::vm::ObjectModel;
+
+use crate::abi::LOG_BIT_UNLOGGED;
+use crate::object_model::VMObjectModel;
+
+/// The side metadata spec of the object-granularity unlog bit.
+pub fn log_bit_spec() -> &'static SideMetadataSpec {
+    let spec = VMObjectModel::GLOBAL_LOG_BIT_SPEC
+        .as_spec()
+        .extract_side_spec();
+    assert_inlinable(spec);
+    spec
+}
+
+/// The side metadata spec of the field-granularity unlog bit.  Only plans with field-granularity
+/// barriers reserve memory for it.
+pub fn field_unlog_bit_spec() -> &'static SideMetadataSpec {
+    let spec = VMObjectModel::GLOBAL_FIELD_UNLOG_BIT_SPEC
+        .as_spec()
+        .extract_side_spec();
+    assert_inlinable(spec);
+    spec
+}
+
+/// The inlined fast path assumes one bit per region, and that the metadata of all regions are
+/// laid out contiguously from the base address.
+fn assert_inlinable(spec: &SideMetadataSpec) {
+    assert!(spec.is_global, "{} is not global", spec.name);
+    assert_eq!(
+        spec.log_num_of_bits, 0,
+        "{} is not one bit per region",
+        spec.name
+    );
+}
+
+/// Compute the address of the metadata byte and the bit shift in that byte for `addr` the same
+/// way as the inlined fast path does.
+fn inline_bit_location(
+    base: Address,
+    log_bytes_in_region: usize,
+    addr: Address,
+) -> (Address, usize) {
+    let region_index = addr.as_usize() >> log_bytes_in_region;
+    let byte_addr = base + region_index / BITS_IN_BYTE;
+    let bit_shift = region_index % BITS_IN_BYTE;
+    (byte_addr, bit_shift)
+}
+
+/// Load the bit for `addr` the same way as the inlined fast path does.
+fn load_bit_inline(spec: &SideMetadataSpec, addr: Address) -> u8 {
+    let (byte_addr, bit_shift) =
+        inline_bit_location(spec.get_absolute_offset(), spec.log_bytes_in_region, addr);
+    let byte = unsafe { byte_addr.load::<u8>() };
+    (byte >> bit_shift) & 1
+}
+
+/// Return true if the inlined fast path and mmtk-core agree on whether `addr` is unlogged
+/// according to `spec`.  `what` is for logging.
+fn check_bit(spec: &SideMetadataSpec, addr: Address, what: &str) -> bool {
+    let inline_unlogged = load_bit_inline(spec, addr) == LOG_BIT_UNLOGGED;
+    let rust_unlogged = spec.load_atomic::<u8>(addr, Ordering::SeqCst) == LOG_BIT_UNLOGGED;
+
+    if inline_unlogged != rust_unlogged {
+        error!(
+            "{} mismatch for {what} {addr}: inlined: {inline_unlogged}, mmtk-core: {rust_unlogged}",
+            spec.name
+        );
+    }
+
+    inline_unlogged == rust_unlogged
+}
+
+/// Check the inlined fast path of the write barrier against mmtk-core for writing to `object`.
+///
+/// If `slot` is `Some`, the VM inlines the field-granularity check for that field.  Otherwise it
+/// inlines the object-granularity check.  It checks that the object unlog bit, and the field unlog
+/// bit if `slot` is `Some`, computed inline agree with mmtk-core.
+///
+/// It only reads metadata.  It never takes the slow path, so it does not log objects or fields.
+pub fn self_check(object: ObjectReference, slot: Option<Address>) -> bool {
+    let mut ok = check_bit(log_bit_spec(), object.to_raw_address(), "object");
+    if let Some(slot) = slot {
+        ok &= check_bit(field_unlog_bit_spec(), slot, "field");
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_bit_location_matches_side_metadata_layout() {
+        let base = Address::from_usize(0x1000_0000);
+        // One bit per 8 bytes: 64 bytes are covered by one metadata byte.
+        assert_eq!(
+            inline_bit_location(base, 3, Address::from_usize(0x0)),
+            (base, 0)
+        );
+        assert_eq!(
+            inline_bit_location(base, 3, Address::from_usize(0x38)),
+            (base, 7)
+        );
+        assert_eq!(
+            inline_bit_location(base, 3, Address::from_usize(0x40)),
+            (base + 1usize, 0)
+        );
+        assert_eq!(
+            inline_bit_location(base, 3, Address::from_usize(0x7f00_0000_0048)),
+            (base + 0x7f00_0000_0048usize / 64, 1)
+        );
+        // One bit per 16 bytes.
+        assert_eq!(
+            inline_bit_location(base, 4, Address::from_usize(0x90)),
+            (base + 1usize, 1)
+        );
+    }
+}
@@ -1,8 +1,10 @@
+use std::borrow::Cow;
 use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::Ordering;
 
-use crate::abi::{RubyObjectAccess, MIN_OBJ_ALIGN, OBJREF_OFFSET};
+use crate::abi::{RubyObjectAccess, OBJREF_OFFSET, VALUE};
 use crate::{abi, Ruby};
-use mmtk::util::constants::BITS_IN_BYTE;
+use mmtk::util::constants::{BITS_IN_BYTE, BYTES_IN_WORD};
 use mmtk::util::copy::{CopySemantics, GCWorkerCopyContext};
 use mmtk::util::{Address, ObjectReference};
 use mmtk::vm::*;
@@ -51,9 +53,12 @@ impl ObjectModel<Ruby> for VMObjectModel {
         let has_exivar = from_acc.has_exivar();
         let from_start = from_acc.obj_start();
         let object_size = from_acc.object_size();
-        let to_start = copy_context.alloc_copy(from, object_size, MIN_OBJ_ALIGN, 0, semantics);
+        let align = from_acc.align_when_copied();
+        let offset = from_acc.align_offset_when_copied();
+        let to_start = copy_context.alloc_copy(from, object_size, align, offset, semantics);
         debug_assert!(!to_start.is_zero());
         let to_payload = to_start.add(OBJREF_OFFSET);
+        debug_assert!(to_payload.is_aligned_to(from_acc.payload_align()));
         unsafe {
             copy_nonoverlapping::<u8>(from_start.to_ptr(), to_start.to_mut_ptr(), object_size);
         }
@@ -76,9 +81,8 @@ impl ObjectModel<Ruby> for VMObjectModel {
         }
 
         if has_exivar {
-            let mut backwarding_table = crate::binding().backwarding_table.lock().unwrap();
             trace!("Inserting into backwarding table: from: {from} <- to_obj: {to_obj}");
-            backwarding_table.insert(to_obj, from);
+            crate::binding().backwarding_table.insert(to_obj, from);
         } else {
             trace!("No exivar: from: {from} <- to_obj: {to_obj}");
         }
@@ -91,15 +95,23 @@ impl ObjectModel<Ruby> for VMObjectModel {
         semantics: CopySemantics,
         copy_context: &mut GCWorkerCopyContext<Ruby>,
     ) -> Option<ObjectReference> {
+        let try_copy_failure = &crate::binding().try_copy_failure;
+        if try_copy_failure.should_fail(from) {
+            return None;
+        }
         let from_acc = RubyObjectAccess::from_objref(from);
         let has_exivar = from_acc.has_exivar();
         let from_start = from_acc.obj_start();
         let object_size = from_acc.object_size();
-        let to_start = copy_context.alloc_copy(from, object_size, MIN_OBJ_ALIGN, 0, semantics);
+        let align = from_acc.align_when_copied();
+        let offset = from_acc.align_offset_when_copied();
+        let to_start = copy_context.alloc_copy(from, object_size, align, offset, semantics);
         if to_start.is_zero() {
+            try_copy_failure.on_real_failure(from);
             return None;
         }
         let to_payload = to_start.add(OBJREF_OFFSET);
+        debug_assert!(to_payload.is_aligned_to(from_acc.payload_align()));
         unsafe {
             copy_nonoverlapping::<u8>(from_start.to_ptr(), to_start.to_mut_ptr(), object_size);
         }
@@ -122,9 +134,8 @@ impl ObjectModel<Ruby> for VMObjectModel {
         }
 
         if has_exivar {
-            let mut backwarding_table = crate::binding().backwarding_table.lock().unwrap();
             trace!("Inserting into backwarding table: from: {from} <- to_obj: {to_obj}");
-            backwarding_table.insert(to_obj, from);
+            crate::binding().backwarding_table.insert(to_obj, from);
         } else {
             trace!("No exivar: from: {from} <- to_obj: {to_obj}");
         }
@@ -132,24 +143,42 @@ impl ObjectModel<Ruby> for VMObjectModel {
         Some(to_obj)
     }
 
-    fn copy_to(_from: ObjectReference, _to: ObjectReference, _region: Address) -> Address {
-        unimplemented!(
-            "This function cannot be called because we do not support MarkCompact for Ruby."
-        )
+    fn copy_to(from: ObjectReference, to: ObjectReference, region: Address) -> Address {
+        let from_acc = RubyObjectAccess::from_objref(from);
+        let from_start = from_acc.obj_start();
+        let object_size = from_acc.object_size();
+        let to_start = RubyObjectAccess::from_objref(to).obj_start();
+        debug_assert!(region.is_zero() || region <= to_start);
+
+        if from != to {
+            // MarkCompact slides objects towards lower addresses, so the old and the new copies may
+            // overlap.  Note that we don't clear the old copy even with the `clear_old_copy`
+            // feature because it may overlap with the new copy.
+            unsafe {
+                std::ptr::copy::<u8>(from_start.to_ptr(), to_start.to_mut_ptr(), object_size);
+            }
+            trace!("Slid object from {} to {}", from, to);
+        }
+
+        // We don't need to record the old address in the backwarding table.  MarkCompact forwards
+        // weak tables in `forward_weak_refs` before any object is moved, so the VM can still look
+        // up generic fields using the old address at that time.
+
+        to_start + object_size
     }
 
-    fn get_reference_when_copied_to(_from: ObjectReference, _to: Address) -> ObjectReference {
-        unimplemented!(
-            "This function cannot be called because we do not support MarkCompact for Ruby."
-        )
+    fn get_reference_when_copied_to(_from: ObjectReference, to: Address) -> ObjectReference {
+        debug_assert!(!to.is_zero());
+        // unsafe: `to` is the start of the reserved region, and cannot be zero.
+        unsafe { ObjectReference::from_raw_address_unchecked(to + OBJREF_OFFSET) }
     }
 
     fn get_current_size(object: ObjectReference) -> usize {
         RubyObjectAccess::from_objref(object).object_size()
     }
 
-    fn get_type_descriptor(_reference: ObjectReference) -> &'static [i8] {
-        todo!()
+    fn get_type_descriptor(reference: ObjectReference) -> &'static [i8] {
+        RubyObjectAccess::from_objref(reference).type_descriptor()
     }
 
     fn ref_to_object_start(object: ObjectReference) -> Address {
@@ -164,15 +193,86 @@ impl ObjectModel<Ruby> for VMObjectModel {
         Self::get_current_size(object)
     }
 
-    fn get_align_when_copied(_object: ObjectReference) -> usize {
-        todo!()
+    fn get_align_when_copied(object: ObjectReference) -> usize {
+        RubyObjectAccess::from_objref(object).align_when_copied()
     }
 
-    fn get_align_offset_when_copied(_object: ObjectReference) -> usize {
-        todo!()
+    fn get_align_offset_when_copied(object: ObjectReference) -> usize {
+        RubyObjectAccess::from_objref(object).align_offset_when_copied()
     }
 
-    fn dump_object(_object: ObjectReference) {
-        todo!()
+    fn dump_object(object: ObjectReference) {
+        Self::dump_ruby_object(object);
+    }
+}
+
+impl VMObjectModel {
+    /// The maximum number of payload words printed by `dump_ruby_object`.
+    const DUMP_MAX_FIELDS: usize = 8;
+
+    /// Log an object for debugging.  It is intended to be called from mmtk-core diagnostics, or
+    /// from gdb via `mmtk_dump_object`.  It prints what it can without crashing if the hidden
+    /// header is corrupted.
+    ///
+    /// It may be called while the world is stopped, so it does not call into the VM then.  In
+    /// that case, the class is printed by address only.
+    pub fn dump_ruby_object(object: ObjectReference) {
+        let acc = RubyObjectAccess::from_objref(object);
+        info!(
+            "Object {object} (start: {}, payload: {})",
+            acc.obj_start(),
+            acc.payload_addr()
+        );
+
+        let prefix = acc.hidden_header_prefix();
+        if !acc.is_hidden_header_sane() {
+            error!("  Hidden header: {prefix:#x} (corrupted)");
+            return;
+        }
+        let payload_size = acc.payload_size();
+        info!(
+            "  Hidden header: {prefix:#x}, payload size: {payload_size}, payload align: {}, suffix size: {}",
+            acc.payload_align(),
+            RubyObjectAccess::suffix_size()
+        );
+
+        let flags = acc.load_flags();
+        let builtin_type = acc.builtin_type();
+        let world_stopped = crate::binding().world_stopped.load(Ordering::SeqCst);
+        let class_name: Cow<str> = if world_stopped {
+            "(not queried while the world is stopped)".into()
+        } else {
+            acc.class_name()
+                .map_or("anonymous".into(), |name| name.to_string_lossy())
+        };
+        info!(
+            "  Flags: {flags:#x}, type: {} ({builtin_type:#x}), klass: {:#x} ({})",
+            acc.type_name().to_string_lossy(),
+            acc.load_klass().0,
+            class_name,
+        );
+
+        info!(
+            "  Pinned: {}, forwarded to: {:?}, live: {}, reachable: {}",
+            mmtk::memory_manager::is_pinned(object),
+            object.get_forwarded_object(),
+            object.is_live(),
+            object.is_reachable(),
+        );
+        crate::mmtk().debug_print_object_info(object);
+
+        let num_fields = (payload_size / BYTES_IN_WORD).min(Self::DUMP_MAX_FIELDS);
+        for i in 0..num_fields {
+            let field_addr = acc.payload_addr() + i * BYTES_IN_WORD;
+            let value = unsafe { field_addr.load::<VALUE>() };
+            let kind = if value.is_special_const() {
+                "special const"
+            } else if crate::is_mmtk_object_safe(Address::from_usize(value.0)) {
+                "reference"
+            } else {
+                "non-reference"
+            };
+            info!("  [{i}] {field_addr}: {:#x} ({kind})", value.0);
+        }
     }
 }
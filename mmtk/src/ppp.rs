@@ -37,6 +37,9 @@ impl PPPRegistry {
     pub fn pin_ppp_children(&self, tls: VMWorkerThread) {
         log::debug!("Pin children of PPPs...");
 
+        // This includes nursery GCs of StickyImmix if the nursery is moving.  Old children do not
+        // move in nursery GCs, but we pin them anyway because we cannot tell them apart from young
+        // children without tracing.
         if !crate::mmtk().get_plan().current_gc_may_move_object() {
             log::debug!("The current GC is non-moving.  Skipped pinning PPP children.");
             return;
@@ -66,6 +69,13 @@ impl PPPRegistry {
         }
     }
 
+    /// Forward PPPs after MarkCompact has computed the new addresses of objects.  Dead PPPs
+    /// have been removed by `cleanup_ppps`.  We don't check liveness again because MarkCompact
+    /// reuses mark bits when computing forwarding addresses.
+    pub fn forward_ppps(&self, worker: &mut GCWorker<Ruby>) {
+        worker.scheduler().work_buckets[WorkBucketStage::VMRefForwarding].add(ForwardPPPs);
+    }
+
     pub fn cleanup_ppps(&self, worker: &mut GCWorker<Ruby>) {
         worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].add(RemoveDeadPPPs);
         if crate::mmtk().get_plan().current_gc_may_move_object() {
@@ -213,6 +223,23 @@ impl GCWork<Ruby> for RemoveDeadPPPs {
     }
 }
 
+struct ForwardPPPs;
+
+impl GCWork<Ruby> for ForwardPPPs {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
+        let mut ppps = crate::binding()
+            .ppp_registry
+            .ppps
+            .try_lock()
+            .expect("PPPRegistry::ppps should not have races during GC.");
+
+        for obj in ppps.iter_mut() {
+            *obj = obj.get_forwarded_object().unwrap_or(*obj);
+        }
+        log::debug!("Forwarded {} PPPs", ppps.len());
+    }
+}
+
 struct UnpinPPPChildren {
     children: Vec<ObjectReference>,
 }
@@ -1,3 +1,4 @@
+use crate::weak_proc::weak_refs;
 use crate::Ruby;
 use mmtk::util::ObjectReference;
 use mmtk::util::VMWorkerThread;
@@ -6,21 +7,27 @@ use mmtk::vm::ReferenceGlue;
 pub struct VMReferenceGlue {}
 
 impl ReferenceGlue<Ruby> for VMReferenceGlue {
+    // Finalizers are handled by `weak_proc::finalizers`, not by mmtk-core.
     type FinalizableType = ObjectReference;
 
-    fn get_referent(_object: ObjectReference) -> Option<ObjectReference> {
-        unimplemented!()
+    fn get_referent(object: ObjectReference) -> Option<ObjectReference> {
+        weak_refs::get_referent(object)
     }
 
-    fn set_referent(_reff: ObjectReference, _referent: ObjectReference) {
-        unimplemented!()
+    fn set_referent(reff: ObjectReference, referent: ObjectReference) {
+        weak_refs::set_referent(reff, referent)
     }
 
-    fn enqueue_references(_references: &[ObjectReference], _tls: VMWorkerThread) {
-        unimplemented!()
+    fn enqueue_references(references: &[ObjectReference], _tls: VMWorkerThread) {
+        // Ruby has no reference queues.  Weak and soft references registered with the binding are
+        // processed by `WeakRefRegistry`, not by the reference processors in mmtk-core, so this is
+        // only reached if something else registered references with mmtk-core.
+        for reference in references {
+            trace!("Ignoring cleared reference: {reference}");
+        }
     }
 
-    fn clear_referent(_new_reference: ObjectReference) {
-        unimplemented!()
+    fn clear_referent(new_reference: ObjectReference) {
+        weak_refs::clear_referent(new_reference)
     }
 }
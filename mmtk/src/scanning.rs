@@ -1,9 +1,12 @@
+use std::sync::atomic::Ordering;
+
 use crate::abi::GCThreadTLS;
 
-use crate::utils::ChunkedVecCollector;
+use crate::stack_watermark::STACK_WATERMARK_NONE;
 use crate::{extra_assert, is_mmtk_object_safe, upcalls, Ruby, RubySlot};
+use mmtk::plan::BarrierSelector;
 use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
-use mmtk::util::{ObjectReference, VMWorkerThread};
+use mmtk::util::{Address, ObjectReference, VMWorkerThread};
 use mmtk::vm::{ObjectTracer, ObjectTracerContext, RootsWorkFactory, Scanning, SlotVisitor};
 use mmtk::{Mutator, MutatorContext};
 
@@ -36,7 +39,14 @@ impl Scanning<Ruby> for VMScanning {
             is_mmtk_object_safe(object.to_raw_address()),
             "Not an MMTk object: {object}",
         );
+        // During concurrent marking, this is the only work that runs while mutators are running.
+        // Other work packets of the binding, including root scanning and weak reference
+        // processing, run in pauses.  The VM must make `scan_object_ruby_style` safe against
+        // concurrent writes by mutators.
         let gc_tls = unsafe { GCThreadTLS::from_vwt_check(tls) };
+        crate::binding().object_histogram.record(gc_tls, object);
+        let heap_verifier = &crate::binding().heap_verifier;
+        let ordinal = gc_tls.worker().ordinal;
         let visit_object = |_worker, target_object: ObjectReference, pin| {
             trace!(
                 "Tracing edge: {} -> {}{}",
@@ -49,6 +59,9 @@ impl Scanning<Ruby> for VMScanning {
                 "Destination is not an MMTk object. Src: {object} dst: {target_object}"
             );
             let forwarded_target = object_tracer.trace_object(target_object);
+            if heap_verifier.is_enabled() {
+                heap_verifier.record_edge(ordinal, object, forwarded_target);
+            }
             if forwarded_target != target_object {
                 trace!(
                     "  Forwarded target {} -> {}",
@@ -65,8 +78,8 @@ impl Scanning<Ruby> for VMScanning {
             });
     }
 
-    fn notify_initial_thread_scan_complete(_partial_scan: bool, _tls: VMWorkerThread) {
-        // Do nothing
+    fn notify_initial_thread_scan_complete(partial_scan: bool, _tls: VMWorkerThread) {
+        debug!("Initial thread scan complete.  partial_scan: {partial_scan}");
     }
 
     fn scan_roots_in_mutator_thread(
@@ -75,8 +88,29 @@ impl Scanning<Ruby> for VMScanning {
         mut factory: impl RootsWorkFactory<RubySlot>,
     ) {
         let gc_tls = unsafe { GCThreadTLS::from_vwt_check(tls) };
+        let mutator_tls = mutator.get_tls();
+
+        // Frames older than the watermark have been scanned in a previous GC, and have not been
+        // modified since then.  Everything they refer to has survived that GC, and is old.  We
+        // can skip them in nursery GCs, but not in full-heap GCs.  The write barrier checker
+        // needs all frames as roots for its full-heap mark.
+        let is_nursery_gc = (crate::mmtk().get_plan().generational())
+            .is_some_and(|gen| gen.is_current_gc_nursery());
+        let watermark = if Self::supports_return_barrier()
+            && is_nursery_gc
+            && !crate::binding().wb_checker.is_enabled()
+        {
+            crate::binding().stack_watermarks.get(mutator)
+        } else {
+            STACK_WATERMARK_NONE
+        };
+
         Self::collect_object_roots_in("scan_thread_root", gc_tls, &mut factory, || {
-            (upcalls().scan_roots_in_mutator_thread)(mutator.get_tls(), tls);
+            if watermark != STACK_WATERMARK_NONE {
+                (upcalls().scan_roots_in_mutator_thread_partial)(mutator_tls, tls, watermark);
+            } else {
+                (upcalls().scan_roots_in_mutator_thread)(mutator_tls, tls);
+            }
         });
     }
 
@@ -89,40 +123,49 @@ impl Scanning<Ruby> for VMScanning {
             Box::new(ScanYjitRoots::new(factory.clone())),
             Box::new(ScanGlobalSymbolsRoots::new(factory.clone())),
             Box::new(ScanFinalizerTblRoots::new(factory.clone())),
+            Box::new(ScanFinalizerRoots {
+                factory: factory.clone(),
+            }),
             Box::new(ScanMiscRoots::new(factory.clone())),
             Box::new(ScanFinalJobsRoots::new(factory.clone())),
         ];
         gc_tls.worker().scheduler().work_buckets[WorkBucketStage::Prepare]
             .bulk_add(root_scanning_work_packets);
 
-        // Generate WB-unprotected roots scanning work packets
+        // Generate WB-unprotected roots scanning work packets.
+        //
+        // Nursery GCs need them because WB-unprotected objects may point to young objects without
+        // being remembered.
+        //
+        // Snapshot-at-the-beginning barriers don't see writes to WB-unprotected objects, so the
+        // old values of their fields may be lost during concurrent marking.  We add the children
+        // of all WB-unprotected objects, reachable or not, to the snapshot when marking starts.
+        // This may keep some dead objects alive until the next GC.  When re-scanning roots at the
+        // end of concurrent marking, we scan reachable WB-unprotected objects again.
 
         'gen_wb_unprotected_work: {
             let is_nursery_gc = (crate::mmtk().get_plan().generational())
                 .is_some_and(|gen| gen.is_current_gc_nursery());
-            if !is_nursery_gc {
+            let is_re_scanning = crate::binding()
+                .roots_re_scanning
+                .swap(false, Ordering::SeqCst);
+            let is_snapshot = !is_re_scanning
+                && crate::mmtk().get_plan().constraints().barrier == BarrierSelector::SATBBarrier;
+            if !is_nursery_gc && !is_re_scanning && !is_snapshot {
                 break 'gen_wb_unprotected_work;
             }
 
-            let vecs = {
-                let guard = crate::binding()
-                    .wb_unprotected_objects
-                    .try_lock()
-                    .expect("Someone is holding the lock of wb_unprotected_objects?");
-                if guard.is_empty() {
-                    break 'gen_wb_unprotected_work;
-                }
-
-                let mut collector = ChunkedVecCollector::new(128);
-                collector.extend(guard.iter().copied());
-                collector.into_vecs()
-            };
-
-            let packets = vecs
+            let packets = crate::binding()
+                .wb_unprotected_objects
+                .dirty_chunks()
                 .into_iter()
-                .map(|objects| {
+                .map(|chunk| {
                     let factory = factory.clone();
-                    Box::new(ScanWbUnprotectedRoots { factory, objects }) as _
+                    Box::new(ScanWbUnprotectedRoots {
+                        factory,
+                        chunk,
+                        include_unreachable: is_snapshot,
+                    }) as _
                 })
                 .collect::<Vec<_>>();
 
@@ -131,29 +174,48 @@ impl Scanning<Ruby> for VMScanning {
     }
 
     fn supports_return_barrier() -> bool {
-        false
+        crate::binding().options.return_barrier
     }
 
     fn prepare_for_roots_re_scanning() {
-        todo!()
+        // MarkCompact also calls this before its second trace, which computes new addresses.
+        // That trace visits every live object, including WB-unprotected objects, so it doesn't
+        // need them as roots.
+        if !crate::mmtk()
+            .get_plan()
+            .constraints()
+            .needs_forward_after_liveness
+        {
+            // Mutators may have written to WB-unprotected objects during concurrent marking.  Let
+            // `scan_vm_specific_roots` scan them again.
+            crate::binding()
+                .roots_re_scanning
+                .store(true, Ordering::SeqCst);
+        }
     }
 
     fn process_weak_refs(
         worker: &mut GCWorker<Ruby>,
         tracer_context: impl ObjectTracerContext<Ruby>,
     ) -> bool {
-        crate::binding()
+        let need_retrace = crate::binding()
             .weak_proc
             .process_weak_stuff(worker, tracer_context);
+        if need_retrace {
+            return true;
+        }
         crate::binding().ppp_registry.cleanup_ppps(worker);
         false
     }
 
     fn forward_weak_refs(
-        _worker: &mut GCWorker<Ruby>,
-        _tracer_context: impl ObjectTracerContext<Ruby>,
+        worker: &mut GCWorker<Ruby>,
+        tracer_context: impl ObjectTracerContext<Ruby>,
     ) {
-        panic!("We can't use MarkCompact in Ruby.");
+        crate::binding()
+            .weak_proc
+            .forward_weak_stuff(worker, tracer_context);
+        crate::binding().ppp_registry.forward_ppps(worker);
     }
 }
 
@@ -161,11 +223,17 @@ impl VMScanning {
     const OBJECT_BUFFER_SIZE: usize = 4096;
 
     fn collect_object_roots_in<F: FnOnce()>(
-        root_scan_kind: &str,
+        root_scan_kind: &'static str,
         gc_tls: &mut GCThreadTLS,
         factory: &mut impl RootsWorkFactory<RubySlot>,
         callback: F,
     ) {
+        let heap_verifier = &crate::binding().heap_verifier;
+        // The children of WB-unprotected objects are not real roots.  The full-heap mark of the
+        // write barrier checker reaches them from the real roots.
+        let wb_checker = &crate::binding().wb_checker;
+        let record_wb_check_roots = wb_checker.is_enabled() && root_scan_kind != "wb_unprot_roots";
+        let ordinal = gc_tls.worker().ordinal;
         let mut buffer: Vec<ObjectReference> = Vec::new();
         let visit_object = |_, object: ObjectReference, pin| {
             debug!(
@@ -184,6 +252,12 @@ impl VMScanning {
             );
             buffer.push(object);
             if buffer.len() >= Self::OBJECT_BUFFER_SIZE {
+                if heap_verifier.is_enabled() {
+                    heap_verifier.record_roots(ordinal, &buffer, root_scan_kind);
+                }
+                if record_wb_check_roots {
+                    wb_checker.record_roots(ordinal, &buffer);
+                }
                 factory.create_process_pinning_roots_work(std::mem::take(&mut buffer));
             }
             object
@@ -193,6 +267,12 @@ impl VMScanning {
             .set_temporarily_and_run_code(visit_object, callback);
 
         if !buffer.is_empty() {
+            if heap_verifier.is_enabled() {
+                heap_verifier.record_roots(ordinal, &buffer, root_scan_kind);
+            }
+            if record_wb_check_roots {
+                wb_checker.record_roots(ordinal, &buffer);
+            }
             factory.create_process_pinning_roots_work(buffer);
         }
     }
@@ -275,17 +355,60 @@ define_global_root_scanner!(ScanFinalJobsRoots, {
     (crate::upcalls().scan_final_jobs_roots)();
 });
 
+/// Scan the arrays of finalizer procs and the objects ready for finalization.  They are held by
+/// the binding, not the VM.
+struct ScanFinalizerRoots<F: RootsWorkFactory<RubySlot>> {
+    factory: F,
+}
+
+impl<F: RootsWorkFactory<RubySlot>> GCWork<Ruby> for ScanFinalizerRoots<F> {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let heap_verifier = &crate::binding().heap_verifier;
+        let mut roots = vec![];
+        crate::binding()
+            .weak_proc
+            .finalizers
+            .for_each_root(|object| roots.push(object));
+        debug!("[finalizer_roots] {} roots", roots.len());
+        if heap_verifier.is_enabled() {
+            heap_verifier.record_roots(worker.ordinal, &roots, "finalizer_roots");
+        }
+        let wb_checker = &crate::binding().wb_checker;
+        if wb_checker.is_enabled() {
+            wb_checker.record_roots(worker.ordinal, &roots);
+        }
+        if !roots.is_empty() {
+            self.factory.create_process_pinning_roots_work(roots);
+        }
+    }
+}
+
+/// Scan old WB-unprotected objects during nursery GC.  Their fields may point to young objects
+/// without going through write barriers, so we treat their children as roots.
+///
+/// We don't update the fields of WB-unprotected objects.  Their children are reported as pinning
+/// roots so that they stay in place even if the nursery GC moves young objects.
+///
+/// Each work packet scans the WB-unprotected objects in one chunk of the heap.  If
+/// `include_unreachable` is true, it scans all of them, which is how they are added to the snapshot
+/// of concurrent marking.
 struct ScanWbUnprotectedRoots<F: RootsWorkFactory<RubySlot>> {
     factory: F,
-    objects: Vec<ObjectReference>,
+    chunk: Address,
+    include_unreachable: bool,
 }
 
 impl<F: RootsWorkFactory<RubySlot>> GCWork<Ruby> for ScanWbUnprotectedRoots<F> {
     fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
         let gc_tls = unsafe { GCThreadTLS::from_vwt_check(worker.tls) };
+        let wb_unprotected_objects = &crate::binding().wb_unprotected_objects;
         VMScanning::collect_object_roots_in("wb_unprot_roots", gc_tls, &mut self.factory, || {
-            for object in self.objects.iter().copied() {
-                if object.is_reachable() {
+            wb_unprotected_objects.for_each_in_chunk(self.chunk, |object| {
+                // Nothing has been traced in the Prepare stage, yet.  Old objects are still marked
+                // from previous GCs, so `is_reachable` tells old objects from young ones even if
+                // the nursery is moving.  Young objects are scanned (and have their fields
+                // updated) when they are traced like other objects.
+                if self.include_unreachable || object.is_reachable() {
                     debug!(
                         "[wb_unprot_roots] Visiting WB-unprotected object (parent): {}",
                         object
@@ -297,7 +420,7 @@ impl<F: RootsWorkFactory<RubySlot>> GCWork<Ruby> for ScanWbUnprotectedRoots<F> {
                         object
                     );
                 }
-            }
+            });
         });
     }
 }
@@ -0,0 +1,95 @@
+use mmtk::util::constants::BYTES_IN_WORD;
+use mmtk::util::{Address, ObjectReference};
+use mmtk::vm::slot::MemorySlice;
+
+use crate::abi::VALUE;
+use crate::RubySlot;
+
+/// A contiguous array of `VALUE`s, such as the elements of an `Array`, the fields of a `T_OBJECT`,
+/// or the content of an `imemo:objbuf`.
+///
+/// It is used by array-copy barriers so that bulk operations like `Array#replace` and
+/// `Array#concat` can be handled as one memory region instead of one write barrier per element.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RubyMemorySlice {
+    /// The object that contains the slice, if known.  Generational plans check the state of this
+    /// object instead of the address of the slice, which matters when the slice is in a buffer
+    /// separate from the object.
+    owner: Option<ObjectReference>,
+    /// The address of the first `VALUE`.
+    start: Address,
+    /// The number of `VALUE`s in the slice.
+    num_values: usize,
+}
+
+impl RubyMemorySlice {
+    pub fn new(owner: Option<ObjectReference>, start: Address, num_values: usize) -> Self {
+        debug_assert!(
+            start.is_aligned_to(BYTES_IN_WORD),
+            "Memory slice is not word-aligned: {start}"
+        );
+        Self {
+            owner,
+            start,
+            num_values,
+        }
+    }
+}
+
+impl MemorySlice for RubyMemorySlice {
+    type SlotType = RubySlot;
+    type SlotIterator = RubyMemorySliceSlotIterator;
+
+    fn iter_slots(&self) -> Self::SlotIterator {
+        RubyMemorySliceSlotIterator {
+            cursor: self.start,
+            limit: self.start + self.bytes(),
+        }
+    }
+
+    fn object(&self) -> Option<ObjectReference> {
+        self.owner
+    }
+
+    fn start(&self) -> Address {
+        self.start
+    }
+
+    fn bytes(&self) -> usize {
+        self.num_values * BYTES_IN_WORD
+    }
+
+    fn copy(src: &Self, tgt: &Self) {
+        debug_assert_eq!(src.num_values, tgt.num_values);
+        // Use `copy` instead of `copy_nonoverlapping` because Ruby may move elements within the
+        // same array, such as in `Array#shift` and `Array#insert`.
+        unsafe {
+            std::ptr::copy::<VALUE>(src.start.to_ptr(), tgt.start.to_mut_ptr(), src.num_values);
+        }
+    }
+}
+
+/// Iterate through slots in a `RubyMemorySlice`.
+///
+/// A `VALUE` array may contain special constants such as fixnums, flonums, static symbols,
+/// `nil`, `true` and `false`.  They are not references, and the iterator skips them.
+pub struct RubyMemorySliceSlotIterator {
+    cursor: Address,
+    limit: Address,
+}
+
+impl Iterator for RubyMemorySliceSlotIterator {
+    type Item = RubySlot;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.cursor < self.limit {
+            let slot_addr = self.cursor;
+            self.cursor = slot_addr + BYTES_IN_WORD;
+            let value = unsafe { slot_addr.load::<VALUE>() };
+            if !value.is_special_const() {
+                return Some(RubySlot::from_address(slot_addr));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,93 @@
+//! Support for partial scanning of VM stacks using return barriers.
+//!
+//! When a thread's VM stack is scanned, the VM installs a return barrier at the frame boundary it
+//! has scanned up to, and reports that boundary as the "watermark" with
+//! `mmtk_set_stack_watermark`.  The VM moves the watermark past a frame before the frame is
+//! modified, i.e. when the return barrier is hit, and when a block or `binding` writes a local
+//! variable of an older frame through its `ep`.
+//!
+//! After a nursery GC, all objects referenced from scanned frames are old.  Therefore the next
+//! nursery GC only needs to scan the frames newer than the watermark.  Full-heap GCs always scan
+//! the whole stack.
+//!
+//! Each mutator has its own watermark cell, created when the mutator is bound.  Setting and
+//! getting a watermark only takes the lock of the map for reading, so threads hitting return
+//! barriers do not exclude each other.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use crate::api::RubyMutator;
+
+/// The watermark value meaning "scan the whole stack".
+pub const STACK_WATERMARK_NONE: usize = 0;
+
+/// Watermarks of all mutators.
+///
+/// The watermark is opaque to the binding.  It is usually the address of a control frame, and
+/// only the VM knows how to interpret it.
+#[derive(Default)]
+pub struct StackWatermarks {
+    watermarks: RwLock<HashMap<usize, Box<AtomicUsize>>>,
+}
+
+impl StackWatermarks {
+    fn key(mutator: *const RubyMutator) -> usize {
+        mutator as usize
+    }
+
+    /// Create the watermark cell of a new mutator.
+    pub fn add(&self, mutator: *const RubyMutator) {
+        let mut watermarks = self.watermarks.write().unwrap();
+        watermarks.insert(
+            Self::key(mutator),
+            Box::new(AtomicUsize::new(STACK_WATERMARK_NONE)),
+        );
+    }
+
+    /// Forget the watermark of a mutator that is being destroyed.
+    pub fn remove(&self, mutator: *const RubyMutator) {
+        let mut watermarks = self.watermarks.write().unwrap();
+        watermarks.remove(&Self::key(mutator));
+    }
+
+    /// Get the watermark of `mutator`, or `STACK_WATERMARK_NONE` if none is reported.
+    pub fn get(&self, mutator: *const RubyMutator) -> usize {
+        let watermarks = self.watermarks.read().unwrap();
+        watermarks
+            .get(&Self::key(mutator))
+            .map_or(STACK_WATERMARK_NONE, |w| w.load(Ordering::Acquire))
+    }
+
+    /// Set the watermark of `mutator`.  The VM calls this when installing a return barrier after
+    /// scanning the stack, and when moving the watermark to an older frame.
+    pub fn set(&self, mutator: *const RubyMutator, watermark: usize) {
+        let watermarks = self.watermarks.read().unwrap();
+        let cell = watermarks
+            .get(&Self::key(mutator))
+            .unwrap_or_else(|| panic!("Mutator {mutator:?} is not bound"));
+        cell.store(watermark, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watermarks_are_per_mutator() {
+        let watermarks = StackWatermarks::default();
+        let m1 = 0x1000 as *const RubyMutator;
+        let m2 = 0x2000 as *const RubyMutator;
+        watermarks.add(m1);
+        watermarks.add(m2);
+
+        watermarks.set(m1, 0x7000);
+        assert_eq!(watermarks.get(m1), 0x7000);
+        assert_eq!(watermarks.get(m2), STACK_WATERMARK_NONE);
+
+        watermarks.remove(m1);
+        assert_eq!(watermarks.get(m1), STACK_WATERMARK_NONE);
+    }
+}
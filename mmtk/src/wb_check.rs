@@ -0,0 +1,228 @@
+//! A debug mode that detects missing write barriers, similar to CRuby's `RGENGC_CHECK_MODE`.
+//!
+//! When enabled with the `RUBY_MMTK_CHECK_WB` environment variable, it does a full-heap mark after
+//! each nursery GC, in the `Final` stage, i.e. after the release phase.  Starting from the roots
+//! of the nursery GC, it scans every reachable object with `scan_object_ruby_style` in parallel
+//! work packets, and checks that every reference points to an MMTk object.
+//!
+//! A nursery GC only frees young objects.  If a reachable object points to an object freed by the
+//! nursery GC, the source is an old object, and the nursery GC did not see the old-to-young edge.
+//! That happens if the source was neither logged by `mmtk_object_reference_write_post` nor
+//! registered with `mmtk_register_wb_unprotected_object`, because logged objects and
+//! WB-unprotected objects are scanned during nursery GCs.  In other words, some C code must have
+//! written the reference without `RB_OBJ_WRITE`.  The report names the type of the source object.
+//!
+//! The roots are recorded when the nursery GC scans them.  Partial stack scanning is disabled
+//! while checking, so that all frames are scanned.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::{ObjectReference, VMWorkerThread};
+use mmtk::vm::ObjectModel;
+
+use crate::abi::{GCThreadTLS, RubyObjectAccess};
+use crate::object_model::VMObjectModel;
+use crate::utils::AfterAll;
+use crate::{is_mmtk_object_safe, upcalls, Ruby};
+
+/// The maximum number of missing barriers reported in detail.
+const MAX_REPORTED_ERRORS: usize = 16;
+
+/// The number of objects scanned in one work packet.
+const MARK_CHUNK_SIZE: usize = 4096;
+
+/// The number of shards of the set of marked objects.
+const MARKED_SHARDS: usize = 64;
+
+pub struct WriteBarrierChecker {
+    enabled: bool,
+    /// Roots recorded by each GC worker in the current GC.
+    per_worker_roots: Vec<Mutex<Vec<ObjectReference>>>,
+}
+
+impl WriteBarrierChecker {
+    pub fn new(enabled: bool, num_workers: usize) -> Self {
+        Self {
+            enabled,
+            per_worker_roots: (0..num_workers).map(|_| Default::default()).collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Record roots found on the worker `ordinal`.
+    pub fn record_roots(&self, ordinal: usize, objects: &[ObjectReference]) {
+        self.per_worker_roots[ordinal]
+            .lock()
+            .unwrap()
+            .extend_from_slice(objects);
+    }
+
+    fn take_roots(&self) -> Vec<ObjectReference> {
+        self.per_worker_roots
+            .iter()
+            .flat_map(|roots| std::mem::take(&mut *roots.lock().unwrap()))
+            .collect()
+    }
+
+    /// Schedule the check in the `Final` stage if enabled.  Called when the world stops.
+    pub fn schedule_check(&self, tls: VMWorkerThread) {
+        if !self.enabled {
+            return;
+        }
+        self.take_roots();
+
+        let gc_tls = unsafe { GCThreadTLS::from_vwt_check(tls) };
+        gc_tls.worker().scheduler().work_buckets[WorkBucketStage::Final].add(CheckWriteBarriers);
+    }
+}
+
+/// States shared by the work packets of the full-heap mark in one GC.
+struct WbCheck {
+    /// Objects marked by the full-heap mark, sharded by address so that workers rarely contend.
+    marked: Vec<Mutex<HashSet<ObjectReference>>>,
+    num_marked: AtomicUsize,
+    num_errors: AtomicUsize,
+    after_all: AfterAll,
+}
+
+impl WbCheck {
+    /// Mark `object`, and return true if it was not marked before.
+    fn try_mark(&self, object: ObjectReference) -> bool {
+        let shard = (object.to_raw_address().as_usize() >> 4) % MARKED_SHARDS;
+        let newly_marked = self.marked[shard].lock().unwrap().insert(object);
+        if newly_marked {
+            self.num_marked.fetch_add(1, Ordering::Relaxed);
+        }
+        newly_marked
+    }
+
+    /// Count an error, and return true if it should be reported in detail.
+    fn add_error(&self) -> bool {
+        self.num_errors.fetch_add(1, Ordering::Relaxed) < MAX_REPORTED_ERRORS
+    }
+
+    /// Create a packet that scans `objects`, which must have been counted up in `after_all`.
+    fn mark_packet(self: &Arc<Self>, objects: Vec<ObjectReference>) -> Box<dyn GCWork<Ruby>> {
+        Box::new(MarkObjects {
+            objects,
+            check: self.clone(),
+        })
+    }
+}
+
+/// Start the full-heap mark from the roots recorded in this GC, if this GC is a nursery GC.
+struct CheckWriteBarriers;
+
+impl GCWork<Ruby> for CheckWriteBarriers {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let is_nursery_gc = (crate::mmtk().get_plan().generational())
+            .is_some_and(|gen| gen.is_current_gc_nursery());
+        let roots = crate::binding().wb_checker.take_roots();
+        if !is_nursery_gc {
+            return;
+        }
+
+        let check = Arc::new(WbCheck {
+            marked: (0..MARKED_SHARDS).map(|_| Default::default()).collect(),
+            num_marked: AtomicUsize::new(0),
+            num_errors: AtomicUsize::new(0),
+            after_all: AfterAll::new(WorkBucketStage::Final),
+        });
+        check.after_all.add_packets(vec![Box::new(ReportWbCheck {
+            check: check.clone(),
+        })]);
+
+        let roots = roots
+            .into_iter()
+            .filter(|root| check.try_mark(*root))
+            .collect::<Vec<_>>();
+        let packets = roots
+            .chunks(MARK_CHUNK_SIZE)
+            .map(|chunk| check.mark_packet(chunk.to_vec()))
+            .collect::<Vec<_>>();
+
+        // Count one more so that the report is scheduled even if there are no roots.
+        check.after_all.count_up(packets.len() + 1);
+        worker.scheduler().work_buckets[WorkBucketStage::Final].bulk_add(packets);
+        check.after_all.count_down(worker);
+    }
+}
+
+/// Scan marked objects, check their fields, and mark their children.
+struct MarkObjects {
+    objects: Vec<ObjectReference>,
+    check: Arc<WbCheck>,
+}
+
+impl GCWork<Ruby> for MarkObjects {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let gc_tls = unsafe { GCThreadTLS::from_vwt_check(worker.tls) };
+        let check = &self.check;
+        let mut new_packets = vec![];
+        let mut buffer = vec![];
+
+        for object in self.objects.iter().copied() {
+            let visit_object = |_worker, target_object: ObjectReference, _pin| {
+                if !is_mmtk_object_safe(target_object.to_raw_address()) {
+                    if check.add_error() {
+                        let type_name = RubyObjectAccess::from_objref(object).type_name();
+                        let wb_unprotected = crate::binding().is_object_wb_unprotected(object);
+                        error!(
+                            "[WB check] Missing write barrier: old {} {object} -> young {target_object}, which was freed by this nursery GC.  WB-unprotected: {wb_unprotected}",
+                            type_name.to_string_lossy(),
+                        );
+                        VMObjectModel::dump_object(object);
+                    }
+                } else if check.try_mark(target_object) {
+                    buffer.push(target_object);
+                    if buffer.len() >= MARK_CHUNK_SIZE {
+                        new_packets.push(check.mark_packet(std::mem::take(&mut buffer)));
+                    }
+                }
+                target_object
+            };
+            gc_tls
+                .object_closure
+                .set_temporarily_and_run_code(visit_object, || {
+                    (upcalls().scan_object_ruby_style)(object);
+                });
+        }
+
+        if !buffer.is_empty() {
+            new_packets.push(check.mark_packet(buffer));
+        }
+        check.after_all.count_up(new_packets.len());
+        worker.scheduler().work_buckets[WorkBucketStage::Final].bulk_add(new_packets);
+        check.after_all.count_down(worker);
+    }
+}
+
+/// Report the result after the full-heap mark.  If any missing barrier is found, log a summary
+/// and panic.  The details of the first `MAX_REPORTED_ERRORS` errors have been logged by
+/// `MarkObjects`.
+struct ReportWbCheck {
+    check: Arc<WbCheck>,
+}
+
+impl GCWork<Ruby> for ReportWbCheck {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let num_marked = self.check.num_marked.load(Ordering::Relaxed);
+        let num_errors = self.check.num_errors.load(Ordering::Relaxed);
+        debug!("[WB check] Marked {num_marked} objects.  Errors: {num_errors}");
+        if num_errors != 0 {
+            error!(
+                "[WB check] Found {num_errors} old-to-young edges without write barriers in {num_marked} objects reachable after a nursery GC.  The first {} are logged above.",
+                num_errors.min(MAX_REPORTED_ERRORS)
+            );
+            panic!(
+                "[WB check] Found {num_errors} missing write barriers.  See the log for details."
+            );
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! The set of WB-unprotected objects, represented as a bitmap in side metadata.
+//!
+//! There is one bit per `MIN_OBJ_ALIGN` bytes of the heap, held in `WB_UNPROTECTED_BIT_SPEC`, a
+//! local side metadata spec laid out after the other local specs of the binding.  mmtk-core does
+//! not know about this spec, so we map its metadata ourselves, one chunk (`BYTES_IN_CHUNK` bytes)
+//! of the heap at a time, when the first WB-unprotected object in the chunk is registered.  Only
+//! the chunks that ever contain WB-unprotected objects cost memory.  Whether the metadata of a
+//! chunk is mapped is recorded in a per-chunk atomic bitmap, so `contains` is a bit test on that
+//! bitmap followed by a plain metadata load.  Only mapping a chunk takes a lock.
+//!
+//! We remember the chunks that may contain WB-unprotected objects (dirty chunks) in another
+//! per-chunk bitmap, so that GC can visit all WB-unprotected objects by scanning the bitmap of
+//! dirty chunks in parallel, one work packet per chunk.  A chunk is identified by its start
+//! address.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use mmtk::util::conversions::chunk_align_down;
+use mmtk::util::heap::layout::vm_layout::BYTES_IN_CHUNK;
+use mmtk::util::metadata::side_metadata::{
+    SideMetadataContext, SideMetadataOffset, SideMetadataSpec,
+};
+use mmtk::util::{Address, ObjectReference};
+use mmtk::vm::ObjectModel;
+
+use crate::abi::MIN_OBJ_ALIGN;
+use crate::object_model::VMObjectModel;
+
+/// One bit per `MIN_OBJ_ALIGN` bytes.  Set if the object at that address is WB-unprotected.
+pub const WB_UNPROTECTED_BIT_SPEC: SideMetadataSpec = SideMetadataSpec {
+    name: "WbUnprotectedBit",
+    is_global: false,
+    offset: SideMetadataOffset::layout_after(
+        VMObjectModel::LOCAL_LOS_MARK_NURSERY_SPEC
+            .as_spec()
+            .extract_side_spec(),
+    ),
+    log_num_of_bits: 0,
+    log_bytes_in_region: MIN_OBJ_ALIGN.trailing_zeros() as usize,
+};
+
+/// One bit per chunk of the heap range.
+struct ChunkBitmap {
+    heap_start: Address,
+    words: Box<[AtomicU64]>,
+}
+
+impl ChunkBitmap {
+    const BITS_IN_WORD: usize = u64::BITS as usize;
+
+    fn new(heap_start: Address, heap_end: Address) -> Self {
+        let num_chunks = (heap_end - heap_start).div_ceil(BYTES_IN_CHUNK);
+        Self {
+            heap_start,
+            words: (0..num_chunks.div_ceil(Self::BITS_IN_WORD))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    fn word_and_mask(&self, chunk: Address) -> (&AtomicU64, u64) {
+        let index = (chunk - self.heap_start) / BYTES_IN_CHUNK;
+        (
+            &self.words[index / Self::BITS_IN_WORD],
+            1 << (index % Self::BITS_IN_WORD),
+        )
+    }
+
+    fn get(&self, chunk: Address) -> bool {
+        let (word, mask) = self.word_and_mask(chunk);
+        word.load(Ordering::Acquire) & mask != 0
+    }
+
+    fn set(&self, chunk: Address) {
+        let (word, mask) = self.word_and_mask(chunk);
+        word.fetch_or(mask, Ordering::Release);
+    }
+
+    /// Get the chunks whose bits are set.  If `clear` is true, also clear the bits.
+    fn chunks(&self, clear: bool) -> Vec<Address> {
+        let mut chunks = vec![];
+        for (i, word) in self.words.iter().enumerate() {
+            let mut bits = if clear {
+                word.swap(0, Ordering::AcqRel)
+            } else {
+                word.load(Ordering::Acquire)
+            };
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                chunks.push(self.heap_start + (i * Self::BITS_IN_WORD + bit) * BYTES_IN_CHUNK);
+            }
+        }
+        chunks
+    }
+}
+
+pub struct WbUnprotectedObjects {
+    heap_start: Address,
+    heap_end: Address,
+    /// Contains `WB_UNPROTECTED_BIT_SPEC` only.  Used for mapping its metadata.
+    metadata: SideMetadataContext,
+    /// Chunks whose metadata has been mapped.  Chunks are never unmapped.
+    mapped_chunks: ChunkBitmap,
+    /// Serializes mapping.  `mapped_chunks` is only set while holding it.
+    map_lock: Mutex<()>,
+    /// Chunks that may contain WB-unprotected objects.
+    dirty_chunks: ChunkBitmap,
+}
+
+impl WbUnprotectedObjects {
+    pub fn new(heap_start: Address, heap_end: Address) -> Self {
+        debug!("WB-unprotected objects: heap: {heap_start}-{heap_end}");
+        Self {
+            heap_start,
+            heap_end,
+            metadata: SideMetadataContext {
+                global: vec![],
+                local: vec![WB_UNPROTECTED_BIT_SPEC],
+            },
+            mapped_chunks: ChunkBitmap::new(heap_start, heap_end),
+            map_lock: Mutex::new(()),
+            dirty_chunks: ChunkBitmap::new(heap_start, heap_end),
+        }
+    }
+
+    fn is_in_heap(&self, object: ObjectReference) -> bool {
+        let addr = object.to_raw_address();
+        self.heap_start <= addr && addr < self.heap_end
+    }
+
+    fn chunk_of(object: ObjectReference) -> Address {
+        chunk_align_down(object.to_raw_address())
+    }
+
+    fn is_chunk_mapped(&self, chunk: Address) -> bool {
+        self.mapped_chunks.get(chunk)
+    }
+
+    /// Map the metadata for `chunk` unless it has been mapped.
+    fn ensure_chunk_mapped(&self, chunk: Address) {
+        if self.is_chunk_mapped(chunk) {
+            return;
+        }
+        let _guard = self.map_lock.lock().unwrap();
+        if self.is_chunk_mapped(chunk) {
+            return;
+        }
+        self.metadata
+            .try_map_metadata_space(chunk, BYTES_IN_CHUNK, "wb_unprotected")
+            .unwrap_or_else(|e| {
+                panic!("Failed to map the WB-unprotected bitmap for chunk {chunk}: {e}")
+            });
+        // Release: Threads that see the bit also see the mapped metadata.
+        self.mapped_chunks.set(chunk);
+    }
+
+    fn mark_chunk_dirty(&self, chunk: Address) {
+        self.dirty_chunks.set(chunk);
+    }
+
+    pub fn insert(&self, object: ObjectReference) {
+        assert!(
+            self.is_in_heap(object),
+            "WB-unprotected object {object} is out of the heap range {}-{}",
+            self.heap_start,
+            self.heap_end
+        );
+        let chunk = Self::chunk_of(object);
+        self.ensure_chunk_mapped(chunk);
+        let old = WB_UNPROTECTED_BIT_SPEC.fetch_or_atomic::<u8>(
+            object.to_raw_address(),
+            1,
+            Ordering::SeqCst,
+        );
+        if old == 0 {
+            self.mark_chunk_dirty(chunk);
+        }
+    }
+
+    pub fn contains(&self, object: ObjectReference) -> bool {
+        // Objects in chunks not mapped have never been registered.
+        self.is_in_heap(object)
+            && self.is_chunk_mapped(Self::chunk_of(object))
+            && WB_UNPROTECTED_BIT_SPEC.load_atomic::<u8>(object.to_raw_address(), Ordering::SeqCst)
+                != 0
+    }
+
+    /// Only called on objects in dirty chunks, which are mapped.
+    fn remove(&self, object: ObjectReference) {
+        WB_UNPROTECTED_BIT_SPEC.store_atomic::<u8>(object.to_raw_address(), 0, Ordering::SeqCst);
+    }
+
+    /// Get a copy of the list of dirty chunks.
+    pub fn dirty_chunks(&self) -> Vec<Address> {
+        self.dirty_chunks.chunks(false)
+    }
+
+    /// Take the list of dirty chunks.  Chunks that still contain WB-unprotected objects after
+    /// `update_chunk` will be added back.
+    pub fn take_dirty_chunks(&self) -> Vec<Address> {
+        self.dirty_chunks.chunks(true)
+    }
+
+    /// Call `f` on each WB-unprotected object in `chunk`, which must be a dirty chunk.
+    pub fn for_each_in_chunk(&self, chunk: Address, mut f: impl FnMut(ObjectReference)) {
+        debug_assert!(self.is_chunk_mapped(chunk));
+        WB_UNPROTECTED_BIT_SPEC.scan_non_zero_values::<u8>(
+            chunk,
+            chunk + BYTES_IN_CHUNK,
+            &mut |addr| {
+                // unsafe: `addr` is in the heap, and cannot be zero.
+                f(unsafe { ObjectReference::from_raw_address_unchecked(addr) });
+            },
+        );
+    }
+
+    /// Remove dead objects in `chunk`, and move the bits of moved objects to their new addresses.
+    /// Return the numbers of WB-unprotected objects in the chunk before and after the update.
+    ///
+    /// It may be called on different chunks in parallel.  A moved object may be inserted into a
+    /// chunk that is being updated by another worker.  That is harmless because the new copy is
+    /// reachable and not forwarded, so it is kept.
+    pub fn update_chunk(
+        &self,
+        chunk: Address,
+        forward: impl Fn(ObjectReference) -> Option<ObjectReference>,
+    ) -> (usize, usize) {
+        let mut objects = vec![];
+        self.for_each_in_chunk(chunk, |object| objects.push(object));
+
+        let old_size = objects.len();
+        let mut new_size = 0usize;
+        for object in objects {
+            match forward(object) {
+                Some(new_object) if new_object == object => {
+                    new_size += 1;
+                }
+                Some(new_object) => {
+                    trace!("Forwarding WB-unprotected object: {object} -> {new_object}");
+                    self.remove(object);
+                    self.insert(new_object);
+                }
+                None => {
+                    trace!("Removing WB-unprotected object from list: {object}");
+                    self.remove(object);
+                }
+            }
+        }
+
+        if new_size != 0 {
+            self.mark_chunk_dirty(chunk);
+        }
+
+        (old_size, new_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_bitmap_sets_and_takes_chunks() {
+        let heap_start = Address::from_usize(BYTES_IN_CHUNK * 16);
+        let heap_end = heap_start + BYTES_IN_CHUNK * 100;
+        let bitmap = ChunkBitmap::new(heap_start, heap_end);
+        let first = heap_start;
+        let middle = heap_start + BYTES_IN_CHUNK * 64;
+        let last = heap_start + BYTES_IN_CHUNK * 99;
+
+        assert!(!bitmap.get(middle));
+        bitmap.set(first);
+        bitmap.set(middle);
+        bitmap.set(last);
+        assert!(bitmap.get(middle));
+        assert!(!bitmap.get(heap_start + BYTES_IN_CHUNK));
+
+        assert_eq!(bitmap.chunks(false), vec![first, middle, last]);
+        assert_eq!(bitmap.chunks(true), vec![first, middle, last]);
+        assert!(bitmap.chunks(false).is_empty());
+        assert!(!bitmap.get(middle));
+    }
+}
@@ -1,24 +1,36 @@
-use std::sync::Mutex;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 
 use mmtk::{
     scheduler::{GCWork, GCWorker, WorkBucketStage},
-    util::ObjectReference,
-    vm::ObjectTracerContext,
+    util::{Address, ObjectReference},
+    vm::{ObjectTracer, ObjectTracerContext},
 };
 
 use crate::{
-    abi::{self, GCThreadTLS},
+    abi::{self, GCThreadTLS, RubyObjectAccess},
     extra_assert, is_mmtk_object_safe, upcalls,
+    weak_proc::ephemerons::EphemeronRegistry,
+    weak_proc::finalizers::FinalizerRegistry,
+    weak_proc::obj_free_parallel::ProcessObjFreeCandidates,
     weak_proc::weak_global_tables::{
         UpdateCiTable, UpdateFinalizerAndObjIdTables, UpdateFrozenStringsTable,
         UpdateGenericFieldsTbl, UpdateGlobalSymbolsTable, UpdateOverloadedCmeTable,
     },
+    weak_proc::weak_refs::WeakRefRegistry,
+    weak_proc::weak_slots::WeakSlotRegistry,
     Ruby,
 };
 
 pub mod concurrent_set_parallel;
+pub mod ephemerons;
+pub mod finalizers;
+pub mod obj_free_parallel;
 pub mod st_table_parallel;
 pub mod weak_global_tables;
+pub mod weak_refs;
+pub mod weak_slots;
 
 /// Set this to true to use chunked processing optimization for the fstring table.
 const SPECIALIZE_FSTRING_TABLE_PROCESSING: bool = true;
@@ -33,36 +45,182 @@ pub enum WeakConcurrentSetKind {
     GlobalSymbols = abi::MMTK_WEAK_CONCURRENT_SET_KIND_GLOBAL_SYMBOLS,
 }
 
+/// A buffer of `obj_free` candidates registered by one mutator thread.  Only its owner thread
+/// pushes to it, so the lock is uncontended except when it is flushed by another thread.
+type ObjFreeBuffer = Mutex<Vec<ObjectReference>>;
+
+/// The thread-local handle of an `ObjFreeBuffer`.  The buffer is flushed and unregistered when
+/// the thread exits.
+struct LocalObjFreeBuffer {
+    buffer: Arc<ObjFreeBuffer>,
+}
+
+impl Drop for LocalObjFreeBuffer {
+    fn drop(&mut self) {
+        crate::binding()
+            .weak_proc
+            .retire_obj_free_buffer(&self.buffer);
+    }
+}
+
+thread_local! {
+    static LOCAL_OBJ_FREE_BUFFER: RefCell<Option<LocalObjFreeBuffer>> = const { RefCell::new(None) };
+}
+
+/// The phase of tracing objects that are live only if other objects are reachable, namely the
+/// values of ephemerons and the referents of soft references.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ConditionalTracingPhase {
+    /// Not started in the current GC.
+    Idle,
+    /// Tracing, one round per closure.
+    Tracing,
+    /// The last round traced nothing.  Waiting for the final round of weak reference processing.
+    FixedPoint,
+    /// Dead objects have been retained after a fixed point.  They may make more ephemeron keys
+    /// and soft references reachable, so the rounds start again.
+    Resuming,
+}
+
+/// The phase of keeping dead objects alive for deferred `obj_free` and finalization.
+///
+/// Objects that will be retained are dead to the program.  Weak references to them must be
+/// cleared before they are retained, or the program could reach them again through weak tables
+/// and weak references.  So we find them first, clear weak references with them treated as dead,
+/// and only then trace them.  Tracing them keeps all objects reachable from them alive, too.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RetentionPhase {
+    /// Not started in the current GC.
+    Idle,
+    /// Looking for dead objects to retain.
+    Finding,
+    /// Clearing weak references to the dead objects found.
+    Clearing,
+    /// The dead objects have been retained, or there is nothing to retain.
+    Done,
+}
+
+/// What a pass of weak reference processing does.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WeakProcessingMode {
+    /// Clear references to dead objects, but keep dead owners of weak references in the lists.
+    /// Used before retaining dead objects, which may keep some of the owners alive.
+    ClearDead,
+    /// Clear references to dead objects, forward references to live objects, and remove dead
+    /// owners.
+    Final,
+    /// Only forward references.  Dead entries have been removed.  Used in the `VMRefForwarding`
+    /// stage of MarkCompact.
+    Forwarding,
+}
+
 pub struct WeakProcessor {
     /// Objects that needs `obj_free` called when dying.
-    /// If it is a bottleneck, replace it with a lock-free data structure,
-    /// or add candidates in batch.
     obj_free_candidates: Mutex<Vec<ObjectReference>>,
+    /// Per-thread buffers of `obj_free` candidates not yet flushed into `obj_free_candidates`.
+    obj_free_buffers: Mutex<Vec<Arc<ObjFreeBuffer>>>,
+    /// Flush a thread-local buffer when it has this many candidates.
+    obj_free_buffer_size: usize,
+    /// A bit mask of builtin types (`1 << T_xxx`) whose `obj_free` can be called by multiple GC
+    /// workers concurrently.  Dead candidates of other types are freed serially.
+    obj_free_parallel_safe_types: AtomicU32,
     /// Objects that contain weak fields.
     /// They are registered when such objects are allocated.
     objects_with_weak_fields: Mutex<Vec<ObjectReference>>,
-}
-
-impl Default for WeakProcessor {
-    fn default() -> Self {
-        Self::new()
-    }
+    /// Individual weak slots registered by C code.
+    pub weak_slots: WeakSlotRegistry,
+    /// First-class weak reference objects.
+    pub weak_refs: WeakRefRegistry,
+    /// Ephemerons, i.e. key-value pairs that keep the value alive only while the key is reachable.
+    pub ephemerons: EphemeronRegistry,
+    /// Objects with finalizers defined by `ObjectSpace.define_finalizer`.
+    pub finalizers: FinalizerRegistry,
+    conditional_tracing_phase: Mutex<ConditionalTracingPhase>,
+    retention_phase: Mutex<RetentionPhase>,
+    /// True from `forward_weak_stuff` to `post_forwarding` in MarkCompact GCs.
+    forwarding_only: AtomicBool,
+    /// True if the weak tables of the VM have been processed in a `ClearDead` pass in this GC.
+    vm_weak_tables_cleared: AtomicBool,
 }
 
 impl WeakProcessor {
-    pub fn new() -> Self {
+    pub fn new(obj_free_buffer_size: usize, soft_ref_clear_threshold: f64) -> Self {
         Self {
             obj_free_candidates: Mutex::new(Vec::new()),
+            obj_free_buffers: Default::default(),
+            obj_free_buffer_size,
+            obj_free_parallel_safe_types: AtomicU32::new(0),
             objects_with_weak_fields: Default::default(),
+            weak_slots: Default::default(),
+            weak_refs: WeakRefRegistry::new(soft_ref_clear_threshold),
+            ephemerons: Default::default(),
+            finalizers: Default::default(),
+            conditional_tracing_phase: Mutex::new(ConditionalTracingPhase::Idle),
+            retention_phase: Mutex::new(RetentionPhase::Idle),
+            forwarding_only: AtomicBool::new(false),
+            vm_weak_tables_cleared: AtomicBool::new(false),
         }
     }
 
     /// Add an object as a candidate for `obj_free`.
     ///
-    /// Multiple mutators can call it concurrently, so it has `&self`.
+    /// The object is added to a buffer local to the current thread, and the buffer is flushed in
+    /// batches.  Multiple mutators can call it concurrently, so it has `&self`.
     pub fn add_obj_free_candidate(&self, object: ObjectReference) {
-        let mut obj_free_candidates = self.obj_free_candidates.lock().unwrap();
-        obj_free_candidates.push(object);
+        if self.obj_free_buffer_size <= 1 {
+            let mut obj_free_candidates = self.obj_free_candidates.lock().unwrap();
+            obj_free_candidates.push(object);
+            return;
+        }
+
+        LOCAL_OBJ_FREE_BUFFER.with_borrow_mut(|local| {
+            let local = local.get_or_insert_with(|| self.new_obj_free_buffer());
+            let mut buffer = local.buffer.lock().unwrap();
+            buffer.push(object);
+            if buffer.len() >= self.obj_free_buffer_size {
+                self.add_obj_free_candidates(&buffer);
+                buffer.clear();
+            }
+        });
+    }
+
+    fn new_obj_free_buffer(&self) -> LocalObjFreeBuffer {
+        let buffer: Arc<ObjFreeBuffer> =
+            Arc::new(Mutex::new(Vec::with_capacity(self.obj_free_buffer_size)));
+        self.obj_free_buffers.lock().unwrap().push(buffer.clone());
+        LocalObjFreeBuffer { buffer }
+    }
+
+    fn retire_obj_free_buffer(&self, buffer: &Arc<ObjFreeBuffer>) {
+        let mut buffers = self.obj_free_buffers.lock().unwrap();
+        self.flush_obj_free_buffer(buffer);
+        buffers.retain(|b| !Arc::ptr_eq(b, buffer));
+    }
+
+    fn flush_obj_free_buffer(&self, buffer: &ObjFreeBuffer) {
+        let mut buffer = buffer.lock().unwrap();
+        if !buffer.is_empty() {
+            self.add_obj_free_candidates(&buffer);
+            buffer.clear();
+        }
+    }
+
+    /// Flush the buffer of the current thread.  Called when a mutator is destroyed.
+    pub fn flush_current_obj_free_buffer(&self) {
+        LOCAL_OBJ_FREE_BUFFER.with_borrow(|local| {
+            if let Some(local) = local {
+                self.flush_obj_free_buffer(&local.buffer);
+            }
+        });
+    }
+
+    /// Flush the buffers of all threads.  Called when the world stops, and before getting all
+    /// candidates.
+    pub fn flush_all_obj_free_buffers(&self) {
+        let buffers = self.obj_free_buffers.lock().unwrap();
+        for buffer in buffers.iter() {
+            self.flush_obj_free_buffer(buffer);
+        }
     }
 
     /// Add many objects as candidates for `obj_free`.
@@ -75,9 +233,27 @@ impl WeakProcessor {
         }
     }
 
+    /// Set the builtin types whose `obj_free` can be called concurrently.  Bit `T_xxx` of `mask`
+    /// represents the type `T_xxx`.
+    pub fn set_obj_free_parallel_safe_types(&self, mask: u32) {
+        debug!("obj_free parallel-safe types: {mask:#x}");
+        self.obj_free_parallel_safe_types
+            .store(mask, Ordering::SeqCst);
+    }
+
+    fn is_obj_free_parallel_safe(&self, acc: RubyObjectAccess) -> bool {
+        let mask = self.obj_free_parallel_safe_types.load(Ordering::Relaxed);
+        mask & (1 << acc.builtin_type()) != 0
+    }
+
     pub fn get_all_obj_free_candidates(&self) -> Vec<ObjectReference> {
+        self.flush_all_obj_free_buffers();
         let mut obj_free_candidates = self.obj_free_candidates.lock().unwrap();
-        std::mem::take(obj_free_candidates.as_mut())
+        let mut all_candidates = std::mem::take(obj_free_candidates.as_mut());
+        // Objects waiting for the finalizer thread need `obj_free`, too.  The finalizer thread
+        // keeps running, but has nothing left to do.
+        all_candidates.append(&mut crate::binding().deferred_obj_free.take_backlog());
+        all_candidates
     }
 
     pub fn declare_weak_references(&self, object: ObjectReference) {
@@ -87,11 +263,19 @@ impl WeakProcessor {
     }
 
     pub fn get_all_objects_with_weak_fields(&self) -> Vec<ObjectReference> {
-        let mut objects_with_weak_fields = self
-            .objects_with_weak_fields
-            .try_lock()
-            .expect("Should not have contention.");
-        std::mem::take(&mut objects_with_weak_fields)
+        // Weak references are processed while mutators are stopped, even for concurrent plans.
+        // Mutators may declare objects during concurrent marking, but they take the lock, too.
+        // Snapshot-at-the-beginning plans must treat objects allocated during marking as live,
+        // so those objects pass the `is_reachable` check in `ProcessWeakReferences`.
+        let mut objects_with_weak_fields = std::mem::take(
+            &mut *self
+                .objects_with_weak_fields
+                .try_lock()
+                .expect("Should not have contention."),
+        );
+        // An object may be declared more than once.  Process it once.
+        dedup_objects(&mut objects_with_weak_fields);
+        objects_with_weak_fields
     }
 
     pub fn re_add_objects_with_weak_fields(&self, objects: &[ObjectReference]) {
@@ -99,24 +283,249 @@ impl WeakProcessor {
         objects_with_weak_fields.extend_from_slice(objects);
     }
 
+    /// Process weak references.  Return true if it needs to be called again after the objects
+    /// kept alive in this round are traced.
+    ///
+    /// Ephemeron values and soft referents are traced first, one round per call, until they
+    /// reach a fixed point.  Then dead objects that need to be kept alive are retained (see
+    /// `RetentionPhase`), and the rounds are repeated until a fixed point is reached again.
+    /// Finally, weak tables and weak references are processed.
     pub fn process_weak_stuff(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        tracer_context: impl ObjectTracerContext<Ruby>,
+    ) -> bool {
+        let stage = WorkBucketStage::VMRefClosure;
+        if self.trace_conditionally_live_objects(worker, stage, tracer_context.clone()) {
+            return true;
+        }
+        if self.retain_dead_objects(worker, stage, tracer_context) {
+            return true;
+        }
+
+        self.finish_conditional_tracing();
+        *self.retention_phase.lock().unwrap() = RetentionPhase::Idle;
+        self.finalizers.forward();
+        worker.add_work(
+            stage,
+            ProcessObjFreeCandidates {
+                stage,
+                defer: false,
+            },
+        );
+        self.schedule_weak_processing_work(worker, stage, WeakProcessingMode::Final);
+        false
+    }
+
+    /// Schedule the work of the current retention phase.  Return true if it needs another closure
+    /// round, or false if all dead objects that need to be kept alive have been retained.
+    fn retain_dead_objects<C: ObjectTracerContext<Ruby>>(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        tracer_context: C,
+    ) -> bool {
+        let deferred_obj_free = &crate::binding().deferred_obj_free;
+        let mut phase = self.retention_phase.lock().unwrap();
+        match *phase {
+            RetentionPhase::Idle => {
+                let has_dying_finalizables = self.finalizers.find_dying();
+                if deferred_obj_free.is_enabled() {
+                    // Dead candidates that need cleanup become pending in `deferred_obj_free`.
+                    worker.add_work(stage, ProcessObjFreeCandidates { stage, defer: true });
+                    *phase = RetentionPhase::Finding;
+                    return true;
+                }
+                if !has_dying_finalizables {
+                    return false;
+                }
+                self.schedule_weak_processing_work(worker, stage, WeakProcessingMode::ClearDead);
+                *phase = RetentionPhase::Clearing;
+                true
+            }
+            RetentionPhase::Finding => {
+                if deferred_obj_free.has_pending() || self.finalizers.has_dying() {
+                    debug!("Clearing weak references to dead objects before retaining them.");
+                    self.schedule_weak_processing_work(
+                        worker,
+                        stage,
+                        WeakProcessingMode::ClearDead,
+                    );
+                    *phase = RetentionPhase::Clearing;
+                    return true;
+                }
+                *phase = RetentionPhase::Done;
+                if !deferred_obj_free.has_backlog() {
+                    return false;
+                }
+                // Objects in the backlog were already dead in earlier GCs, and weak references to
+                // them have been cleared.
+                worker.add_work(stage, RetainDeadObjects { tracer_context });
+                self.resume_conditional_tracing();
+                true
+            }
+            RetentionPhase::Clearing => {
+                worker.add_work(stage, RetainDeadObjects { tracer_context });
+                self.resume_conditional_tracing();
+                *phase = RetentionPhase::Done;
+                true
+            }
+            RetentionPhase::Done => false,
+        }
+    }
+
+    /// Schedule one round of tracing ephemeron values and soft referents.  Return true if it
+    /// needs another closure round, or false if a fixed point has been reached.
+    ///
+    /// Ephemerons and soft references share rounds because tracing one may make the other
+    /// reachable.  The fixed point is reached only when a round traces neither.
+    fn trace_conditionally_live_objects<C: ObjectTracerContext<Ruby>>(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        tracer_context: C,
+    ) -> bool {
+        let mut phase = self.conditional_tracing_phase.lock().unwrap();
+        match *phase {
+            ConditionalTracingPhase::Idle => {
+                self.ephemerons.begin_rounds();
+                self.weak_refs.begin_soft_rounds();
+            }
+            ConditionalTracingPhase::Resuming => {
+                // Ephemerons and soft references that were unreachable at the fixed point are
+                // still pending.  Check them again after the retained objects are traced.
+                debug!("Resuming ephemerons and soft references after retaining dead objects.");
+            }
+            ConditionalTracingPhase::Tracing => {
+                // Take both flags.  Don't short-circuit.
+                let ephemeron_progress = self.ephemerons.take_progress();
+                let soft_progress = self.weak_refs.take_soft_progress();
+                if !ephemeron_progress && !soft_progress {
+                    debug!("Ephemerons and soft references reached a fixed point.");
+                    *phase = ConditionalTracingPhase::FixedPoint;
+                    return false;
+                }
+            }
+            ConditionalTracingPhase::FixedPoint => return false,
+        }
+
+        let ephemerons_scheduled =
+            self.ephemerons
+                .schedule_round(worker, stage, tracer_context.clone());
+        let soft_refs_scheduled = self
+            .weak_refs
+            .schedule_soft_round(worker, stage, tracer_context);
+        let scheduled = ephemerons_scheduled || soft_refs_scheduled;
+        *phase = if scheduled {
+            ConditionalTracingPhase::Tracing
+        } else {
+            ConditionalTracingPhase::FixedPoint
+        };
+        scheduled
+    }
+
+    /// Make the next call of `trace_conditionally_live_objects` schedule another round.  Called
+    /// when scheduling the work that retains dead objects.
+    fn resume_conditional_tracing(&self) {
+        let mut phase = self.conditional_tracing_phase.lock().unwrap();
+        debug_assert_eq!(*phase, ConditionalTracingPhase::FixedPoint);
+        *phase = ConditionalTracingPhase::Resuming;
+    }
+
+    fn finish_conditional_tracing(&self) {
+        let mut phase = self.conditional_tracing_phase.lock().unwrap();
+        debug_assert_eq!(*phase, ConditionalTracingPhase::FixedPoint);
+        *phase = ConditionalTracingPhase::Idle;
+    }
+
+    /// Forward weak references after MarkCompact has computed the new addresses of objects.
+    ///
+    /// MarkCompact is currently rejected by `mmtk_init_binding` because it cannot pin roots found
+    /// by conservative stack scanning, so this is not reached.
+    ///
+    /// When `process_weak_stuff` runs during MarkCompact, no objects have been assigned new
+    /// addresses, yet.  It removes dead entries and frees dead `obj_free` candidates, but leaves
+    /// live entries pointing to the old addresses.  Here we only forward the remaining entries.
+    ///
+    /// MarkCompact reuses mark bits when computing new addresses, so liveness can no longer be
+    /// queried at this time.  Work packets for the binding's own lists skip liveness checks in
+    /// the `VMRefForwarding` stage.  The weak tables are still walked by the VM, so
+    /// `mmtk_is_reachable` reports all objects as reachable until `post_forwarding`.  All dead
+    /// entries have been removed, so that is the truth for every object the VM visits.
+    pub fn forward_weak_stuff(
         &self,
         worker: &mut GCWorker<Ruby>,
         _tracer_context: impl ObjectTracerContext<Ruby>,
     ) {
-        worker.add_work(WorkBucketStage::VMRefClosure, ProcessObjFreeCandidates);
+        let stage = WorkBucketStage::VMRefForwarding;
+        self.forwarding_only.store(true, Ordering::SeqCst);
+        self.finalizers.forward();
+        worker.add_work(stage, ForwardObjFreeCandidates);
+        self.schedule_weak_processing_work(worker, stage, WeakProcessingMode::Forwarding);
+    }
+
+    /// Return true if weak references are being forwarded, and dead entries have been removed.
+    pub fn is_forwarding_only(&self) -> bool {
+        self.forwarding_only.load(Ordering::SeqCst)
+    }
+
+    /// Called in `post_forwarding`.
+    pub fn finish_forwarding(&self) {
+        self.forwarding_only.store(false, Ordering::SeqCst);
+    }
 
-        worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].bulk_add(vec![
-            // BEGIN: Weak tables
+    fn schedule_weak_processing_work(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        mode: WeakProcessingMode,
+    ) {
+        // The weak tables of the VM remove entries of dead objects, and forward the others, in
+        // every mode.  After a `ClearDead` pass, the remaining entries all point to live objects
+        // that have been forwarded.  Objects retained after that pass are no longer in the tables,
+        // so the `Final` pass would find nothing to do.  Skip it.
+        let vm_weak_tables_cleared = match mode {
+            WeakProcessingMode::ClearDead => {
+                self.vm_weak_tables_cleared.store(true, Ordering::SeqCst);
+                false
+            }
+            WeakProcessingMode::Final => self.vm_weak_tables_cleared.swap(false, Ordering::SeqCst),
+            WeakProcessingMode::Forwarding => false,
+        };
+        if vm_weak_tables_cleared {
+            debug!("Weak tables of the VM have been cleared in this GC.  Skipping them.");
+        } else {
+            Self::schedule_vm_weak_table_work(worker, stage);
+        }
+
+        worker.scheduler().work_buckets[stage].add(ProcessWeakReferences { mode });
+
+        self.weak_slots.schedule_processing(worker, stage, mode);
+        self.weak_refs.schedule_processing(worker, stage, mode);
+        self.ephemerons.schedule_finish(worker, stage, mode);
+
+        // WB-unprotected objects are not weak references.  Dead ones may still be retained.
+        if mode != WeakProcessingMode::ClearDead {
+            let forwarding = mode == WeakProcessingMode::Forwarding;
+            let wb_unprotected_packets = crate::binding()
+                .wb_unprotected_objects
+                .take_dirty_chunks()
+                .into_iter()
+                .map(|chunk| Box::new(UpdateWbUnprotectedObjectsChunk { chunk, forwarding }) as _)
+                .collect::<Vec<_>>();
+            worker.scheduler().work_buckets[stage].bulk_add(wb_unprotected_packets);
+        }
+    }
+
+    /// Schedule the work packets that process the weak tables held by the VM.
+    fn schedule_vm_weak_table_work(worker: &mut GCWorker<Ruby>, stage: WorkBucketStage) {
+        worker.scheduler().work_buckets[stage].bulk_add(vec![
             // Note: Follow the order of `rb_gc_vm_weak_table_foreach in `gc.c`
             Box::new(UpdateCiTable) as _,
             Box::new(UpdateOverloadedCmeTable) as _,
             // global symbols table specialized
             Box::new(UpdateFinalizerAndObjIdTables) as _,
             Box::new(UpdateGenericFieldsTbl) as _,
-            // END: Weak tables
-            Box::new(UpdateWbUnprotectedObjectsList) as _,
-            Box::new(ProcessWeakReferences) as _,
         ]);
 
         if SPECIALIZE_FSTRING_TABLE_PROCESSING {
@@ -125,9 +534,10 @@ impl WeakProcessor {
                 (upcalls().get_fstring_table_obj)().into(),
                 WeakConcurrentSetKind::FString,
                 worker,
+                stage,
             );
         } else {
-            worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure]
+            worker.scheduler().work_buckets[stage]
                 .add_boxed(Box::new(UpdateFrozenStringsTable) as _);
         }
 
@@ -137,105 +547,109 @@ impl WeakProcessor {
                 (upcalls().get_global_symbols_table_obj)().into(),
                 WeakConcurrentSetKind::GlobalSymbols,
                 worker,
+                stage,
             );
         } else {
-            worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure]
+            worker.scheduler().work_buckets[stage]
                 .add_boxed(Box::new(UpdateGlobalSymbolsTable) as _);
         }
     }
 }
 
-struct ProcessObjFreeCandidates;
+/// Update the WB-unprotected objects in one chunk of the heap.  If `forwarding`, dead objects have
+/// been removed, and the remaining objects are only forwarded.
+struct UpdateWbUnprotectedObjectsChunk {
+    chunk: Address,
+    forwarding: bool,
+}
 
-impl GCWork<Ruby> for ProcessObjFreeCandidates {
+impl GCWork<Ruby> for UpdateWbUnprotectedObjectsChunk {
     fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
-        // If it blocks, it is a bug.
-        let mut obj_free_candidates = crate::binding()
-            .weak_proc
-            .obj_free_candidates
-            .try_lock()
-            .expect("It's GC time.  No mutators should hold this lock at this time.");
-
-        let old_cands = obj_free_candidates.len();
-        debug!("Total: {} candidates", old_cands);
-
-        let mut freed = 0usize;
-        let mut elided = 0usize;
-
-        // Process obj_free
-        let mut new_candidates = Vec::new();
-
-        for object in obj_free_candidates.iter().copied() {
-            if object.is_reachable() {
-                // Forward and add back to the candidate list.
-                let new_object = object.forward();
-                trace!(
-                    "Forwarding obj_free candidate: {} -> {}",
-                    object,
-                    new_object
-                );
-                new_candidates.push(new_object);
-            } else if (upcalls().obj_needs_cleanup_p)(object) {
-                (upcalls().call_obj_free)(object);
-                freed += 1;
-            } else {
-                elided += 1;
-            }
-        }
+        let (old_size, new_size) = crate::binding()
+            .wb_unprotected_objects
+            .update_chunk(self.chunk, |object| {
+                (self.forwarding || object.is_reachable()).then(|| object.forward())
+            });
+
+        debug!(
+            "Retained {new_size} of {old_size} WB-unprotected objects in chunk {}",
+            self.chunk
+        );
 
-        let new_cands = new_candidates.len();
-        *obj_free_candidates = new_candidates;
         probe!(
             mmtk_ruby,
-            process_obj_free_candidates,
-            old_cands,
-            new_cands,
-            freed,
-            elided,
+            update_wb_unprotected_objects_list,
+            old_size,
+            new_size
         );
     }
 }
 
-struct UpdateWbUnprotectedObjectsList;
+/// Forward the remaining `obj_free` candidates and the deferred objects after MarkCompact has
+/// computed the new addresses of objects.  Dead candidates have been removed in the
+/// `VMRefClosure` stage.
+struct ForwardObjFreeCandidates;
 
-impl GCWork<Ruby> for UpdateWbUnprotectedObjectsList {
+impl GCWork<Ruby> for ForwardObjFreeCandidates {
     fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
-        let mut objects = crate::binding().wb_unprotected_objects.try_lock().expect(
-            "Someone is holding the lock of wb_unprotected_objects during weak processing phase?",
-        );
+        let weak_proc = &crate::binding().weak_proc;
+        weak_proc.flush_all_obj_free_buffers();
+        {
+            let mut obj_free_candidates = weak_proc
+                .obj_free_candidates
+                .try_lock()
+                .expect("It's GC time.  No mutators should hold this lock at this time.");
+            for object in obj_free_candidates.iter_mut() {
+                *object = object.forward();
+            }
+            debug!(
+                "Forwarded {} obj_free candidates",
+                obj_free_candidates.len()
+            );
+        }
 
-        let old_objects = std::mem::take(&mut *objects);
-        let old_size = old_objects.len();
+        let deferred_obj_free = &crate::binding().deferred_obj_free;
+        if deferred_obj_free.is_enabled() {
+            deferred_obj_free.forward(|object| object.forward());
+        }
+    }
+}
 
-        debug!("Updating {old_size} WB-unprotected objects");
+/// Keep the objects waiting for deferred `obj_free` alive, and resurrect dying finalizable
+/// objects.  Weak references to the objects found dead in this GC have been cleared.
+struct RetainDeadObjects<C: ObjectTracerContext<Ruby>> {
+    tracer_context: C,
+}
 
-        for object in old_objects {
-            if object.is_reachable() {
-                // Forward and add back to the candidate list.
-                let new_object = object.forward();
-                trace!(
-                    "Forwarding WB-unprotected object: {} -> {}",
-                    object,
-                    new_object
-                );
-                objects.insert(new_object);
-            } else {
-                trace!("Removing WB-unprotected object from list: {}", object);
+impl<C: ObjectTracerContext<Ruby>> GCWork<Ruby> for RetainDeadObjects<C> {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let binding = crate::binding();
+        let mut resurrected = 0;
+        self.tracer_context.with_tracer(worker, |tracer| {
+            if binding.deferred_obj_free.is_enabled() {
+                binding
+                    .deferred_obj_free
+                    .retain(|object| tracer.trace_object(object));
             }
+            resurrected = binding
+                .weak_proc
+                .finalizers
+                .resurrect(|object| tracer.trace_object(object));
+        });
+
+        if resurrected > 0 {
+            debug!("{resurrected} objects are ready for finalization");
+            (upcalls().schedule_finalization)(worker.tls);
         }
-
-        let new_size = objects.len();
-        debug!("Retained {new_size} live WB-unprotected objects.");
-
-        probe!(
-            mmtk_ruby,
-            update_wb_unprotected_objects_list,
-            old_size,
-            new_size
-        );
     }
 }
 
+/// Sort `objects` by address, and remove duplicates.
+fn dedup_objects(objects: &mut Vec<ObjectReference>) {
+    objects.sort_unstable_by_key(|object| object.to_raw_address());
+    objects.dedup();
+}
+
 // Provide a shorthand `object.forward()`.
 trait Forwardable {
     fn forward(&self) -> Self;
@@ -247,7 +661,10 @@ impl Forwardable for ObjectReference {
     }
 }
 
-struct ProcessWeakReferences;
+/// Process objects with weak fields according to `mode`.
+struct ProcessWeakReferences {
+    mode: WeakProcessingMode,
+}
 
 impl GCWork<Ruby> for ProcessWeakReferences {
     fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
@@ -266,54 +683,62 @@ impl GCWork<Ruby> for ProcessWeakReferences {
 
         let mut live_objects = vec![];
 
+        let forwarding = self.mode == WeakProcessingMode::Forwarding;
         for old_object in objects_with_weak_fields {
             trace!("  Object with weak fields: {old_object}");
-            if old_object.is_reachable() {
-                trace!("    Object {old_object} is live");
-                live += 1;
+            if !forwarding && !old_object.is_reachable() {
+                if self.mode == WeakProcessingMode::ClearDead {
+                    // It may be retained later.
+                    live_objects.push(old_object);
+                }
+                continue;
+            }
+            trace!("    Object {old_object} is live");
+            live += 1;
 
-                // Forward old_object if it is moved.
-                let object = if let Some(new_object) = old_object.get_forwarded_object() {
+            // Forward old_object if it is moved.  When forwarding, the object has not been
+            // moved, yet, so we update its fields at the old address.
+            let object = match old_object.get_forwarded_object() {
+                Some(new_object) if !forwarding => {
                     trace!("    Object is moved: {old_object} -> {new_object}");
                     new_object
-                } else {
-                    old_object
-                };
-
-                // We bind the rb_gc_location method to `ObjectReference::get_forwarded_object`
-                // because we are forwarding references in objects that have weak references.  We
-                // don't trace them.
-                let visit_object = |_worker, target_object: ObjectReference, pin: bool| {
+                }
+                _ => old_object,
+            };
+
+            // We bind the rb_gc_location method to `ObjectReference::get_forwarded_object`
+            // because we are forwarding references in objects that have weak references.  We
+            // don't trace them.
+            let visit_object = |_worker, target_object: ObjectReference, pin: bool| {
+                trace!(
+                    "Forwarding edge: {} -> {}{}",
+                    object,
+                    target_object,
+                    if pin { " pin" } else { "" }
+                );
+                extra_assert!(!pin, "Should not pin when forwarding reference.");
+                extra_assert!(
+                    is_mmtk_object_safe(target_object.to_raw_address()),
+                    "Destination is not an MMTk object. Src: {object} dst: {target_object}"
+                );
+                if let Some(forwarded_target) = target_object.get_forwarded_object() {
                     trace!(
-                        "Forwarding edge: {} -> {}{}",
-                        object,
+                        "  Forwarded target {} -> {}",
                         target_object,
-                        if pin { " pin" } else { "" }
-                    );
-                    extra_assert!(!pin, "Should not pin when forwarding reference.");
-                    extra_assert!(
-                        is_mmtk_object_safe(target_object.to_raw_address()),
-                        "Destination is not an MMTk object. Src: {object} dst: {target_object}"
-                    );
-                    if let Some(forwarded_target) = target_object.get_forwarded_object() {
-                        trace!(
-                            "  Forwarded target {} -> {}",
-                            target_object,
-                            forwarded_target
-                        );
                         forwarded_target
-                    } else {
-                        target_object
-                    }
-                };
-                gc_tls
-                    .object_closure
-                    .set_temporarily_and_run_code(visit_object, || {
-                        (upcalls().handle_weak_references)(object, is_moving_gc);
-                    });
-
-                live_objects.push(object);
-            }
+                    );
+                    forwarded_target
+                } else {
+                    target_object
+                }
+            };
+            gc_tls
+                .object_closure
+                .set_temporarily_and_run_code(visit_object, || {
+                    (upcalls().handle_weak_references)(object, is_moving_gc);
+                });
+
+            live_objects.push(object.forward());
         }
 
         crate::binding()
@@ -324,3 +749,25 @@ impl GCWork<Ruby> for ProcessWeakReferences {
         probe!(mmtk_ruby, process_weak_references, num_objects, live);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obj(addr: usize) -> ObjectReference {
+        ObjectReference::from_raw_address(Address::from_usize(addr)).unwrap()
+    }
+
+    #[test]
+    fn objects_declared_twice_are_processed_once() {
+        let mut objects = vec![
+            obj(0x3000),
+            obj(0x1000),
+            obj(0x3000),
+            obj(0x2000),
+            obj(0x1000),
+        ];
+        dedup_objects(&mut objects);
+        assert_eq!(objects, vec![obj(0x1000), obj(0x2000), obj(0x3000)]);
+    }
+}
@@ -15,6 +15,7 @@ pub fn process_weak_concurrent_set_chunked(
     set: Option<ObjectReference>,
     kind: WeakConcurrentSetKind,
     worker: &mut GCWorker<Ruby>,
+    stage: WorkBucketStage,
 ) {
     let Some(set) = set else {
         debug!("Set {name} is empty.  Skipping.");
@@ -58,7 +59,7 @@ pub fn process_weak_concurrent_set_chunked(
 
     counter.fetch_add(entries_packets.len(), Ordering::SeqCst);
 
-    worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].bulk_add(entries_packets);
+    worker.scheduler().work_buckets[stage].bulk_add(entries_packets);
 }
 
 struct UpdateConcurrentSetEntriesParallel {
@@ -0,0 +1,285 @@
+//! Ephemerons.
+//!
+//! An ephemeron is a Ruby object that holds a key at `EPHEMERON_KEY_OFFSET` and a value at
+//! `EPHEMERON_VALUE_OFFSET` from the object reference, right after `RBasic`.  The VM registers
+//! ephemerons with `mmtk_register_ephemeron`, and does not trace those two fields.  The value is
+//! kept alive only if both the ephemeron and the key are reachable.
+//!
+//! After the transitive closure, we trace the values of ephemerons whose keys are reachable, and
+//! ask for another closure round.  That may make more keys reachable.  We repeat until a round
+//! traces no values (see `WeakProcessor::trace_conditionally_live_objects`), and repeat again
+//! after dead objects are retained for finalization or deferred `obj_free`.  Then the keys and
+//! values of the remaining ephemerons are cleared to `Qfalse` together with other weak tables in
+//! the final round of weak reference processing.
+//!
+//! Special constants as keys are always considered reachable.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::{Address, ObjectReference};
+use mmtk::vm::{ObjectTracer, ObjectTracerContext};
+
+use crate::Ruby;
+
+use super::weak_refs::{load_object_field, WEAK_REF_CLEARED};
+use super::{Forwardable, WeakProcessingMode};
+
+/// The offset of the key field from the object reference of an ephemeron.
+pub const EPHEMERON_KEY_OFFSET: usize = 16;
+
+/// The offset of the value field from the object reference of an ephemeron.
+pub const EPHEMERON_VALUE_OFFSET: usize = 24;
+
+/// The number of ephemerons processed in one work packet.
+const EPHEMERONS_CHUNK_SIZE: usize = 1024;
+
+fn key_slot(ephemeron: ObjectReference) -> Address {
+    ephemeron.to_raw_address() + EPHEMERON_KEY_OFFSET
+}
+
+fn value_slot(ephemeron: ObjectReference) -> Address {
+    ephemeron.to_raw_address() + EPHEMERON_VALUE_OFFSET
+}
+
+/// Forward the object in `slot` if it is a moved heap object.
+fn forward_slot(slot: Address) {
+    if let Some(object) = load_object_field(slot) {
+        let new_object = object.forward();
+        if new_object != object {
+            unsafe { slot.store(new_object) };
+        }
+    }
+}
+
+pub struct EphemeronRegistry {
+    /// All ephemerons when not in GC.
+    ephemerons: Mutex<Vec<ObjectReference>>,
+    /// Ephemerons whose keys have not been found reachable in the current GC.
+    pending: Mutex<Vec<ObjectReference>>,
+    /// Ephemerons whose values have been traced in the current GC.
+    resolved: Mutex<Vec<ObjectReference>>,
+    /// Set if any value is traced in the current round.
+    progress: AtomicBool,
+}
+
+impl Default for EphemeronRegistry {
+    fn default() -> Self {
+        Self {
+            ephemerons: Default::default(),
+            pending: Default::default(),
+            resolved: Default::default(),
+            progress: AtomicBool::new(false),
+        }
+    }
+}
+
+impl EphemeronRegistry {
+    pub fn register(&self, ephemeron: ObjectReference) {
+        self.ephemerons.lock().unwrap().push(ephemeron);
+    }
+
+    /// Make all ephemerons pending.  Called before the first round in a GC.
+    pub(super) fn begin_rounds(&self) {
+        let ephemerons = std::mem::take(&mut *self.ephemerons.lock().unwrap());
+        debug!("Processing {} ephemerons", ephemerons.len());
+        *self.pending.lock().unwrap() = ephemerons;
+        self.progress.store(false, Ordering::SeqCst);
+    }
+
+    /// Return true if any value has been traced since the last call.
+    pub(super) fn take_progress(&self) -> bool {
+        self.progress.swap(false, Ordering::SeqCst)
+    }
+
+    /// Schedule work packets to trace the values of pending ephemerons whose keys are reachable.
+    /// Return false if there are no pending ephemerons.
+    pub(super) fn schedule_round<C: ObjectTracerContext<Ruby>>(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        tracer_context: C,
+    ) -> bool {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return false;
+        }
+
+        debug!("Tracing values of {} pending ephemerons", pending.len());
+        let packets = pending
+            .chunks(EPHEMERONS_CHUNK_SIZE)
+            .map(|chunk| {
+                Box::new(TraceEphemeronValues {
+                    ephemerons: chunk.to_vec(),
+                    tracer_context: tracer_context.clone(),
+                }) as _
+            })
+            .collect::<Vec<_>>();
+        worker.scheduler().work_buckets[stage].bulk_add(packets);
+        true
+    }
+
+    /// Schedule work packets to clear the keys and values of pending ephemerons, and forward
+    /// resolved ephemerons.  In the `ClearDead` mode, only reachable pending ephemerons are
+    /// cleared.  In the `Forwarding` mode, all ephemerons are forwarded.
+    pub fn schedule_finish(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        mode: WeakProcessingMode,
+    ) {
+        let (pending, resolved) = match mode {
+            WeakProcessingMode::ClearDead => {
+                (std::mem::take(&mut *self.pending.lock().unwrap()), vec![])
+            }
+            WeakProcessingMode::Final => (
+                std::mem::take(&mut *self.pending.lock().unwrap()),
+                std::mem::take(&mut *self.resolved.lock().unwrap()),
+            ),
+            WeakProcessingMode::Forwarding => {
+                let ephemerons = std::mem::take(&mut *self.ephemerons.lock().unwrap());
+                (vec![], ephemerons)
+            }
+        };
+        debug!(
+            "Finishing ephemerons.  Pending: {}, resolved: {}",
+            pending.len(),
+            resolved.len()
+        );
+
+        let pending_packets = pending.chunks(EPHEMERONS_CHUNK_SIZE).map(|chunk| {
+            Box::new(FinishEphemerons {
+                ephemerons: chunk.to_vec(),
+                resolved: false,
+                mode,
+            }) as _
+        });
+        let resolved_packets = resolved.chunks(EPHEMERONS_CHUNK_SIZE).map(|chunk| {
+            Box::new(FinishEphemerons {
+                ephemerons: chunk.to_vec(),
+                resolved: true,
+                mode,
+            }) as _
+        });
+        worker.scheduler().work_buckets[stage]
+            .bulk_add(pending_packets.chain(resolved_packets).collect());
+    }
+}
+
+/// Trace the values of ephemerons whose keys are reachable.
+struct TraceEphemeronValues<C: ObjectTracerContext<Ruby>> {
+    ephemerons: Vec<ObjectReference>,
+    tracer_context: C,
+}
+
+impl<C: ObjectTracerContext<Ruby>> GCWork<Ruby> for TraceEphemeronValues<C> {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let mut still_pending = vec![];
+        let mut resolved = vec![];
+
+        self.tracer_context.with_tracer(worker, |tracer| {
+            for ephemeron in self.ephemerons.iter().copied() {
+                // The ephemeron itself may become reachable in a later round.
+                let key_reachable = ephemeron.is_reachable()
+                    && load_object_field(key_slot(ephemeron)).is_none_or(|key| key.is_reachable());
+                if !key_reachable {
+                    still_pending.push(ephemeron);
+                    continue;
+                }
+
+                let ephemeron = ephemeron.forward();
+                forward_slot(key_slot(ephemeron));
+                let value_slot = value_slot(ephemeron);
+                if let Some(value) = load_object_field(value_slot) {
+                    let new_value = tracer.trace_object(value);
+                    trace!("Tracing ephemeron value: {ephemeron}: {value} -> {new_value}");
+                    unsafe { value_slot.store(new_value) };
+                }
+                resolved.push(ephemeron);
+            }
+        });
+
+        let registry = &crate::binding().weak_proc.ephemerons;
+        if !resolved.is_empty() {
+            registry.progress.store(true, Ordering::SeqCst);
+            registry.resolved.lock().unwrap().append(&mut resolved);
+        }
+        if !still_pending.is_empty() {
+            registry.pending.lock().unwrap().append(&mut still_pending);
+        }
+    }
+}
+
+/// Clear or forward ephemerons, and put live ephemerons back into the registry.
+///
+/// In the `ClearDead` mode, cleared ephemerons become resolved, so that their values are not
+/// traced if more objects become reachable later, and dead ephemerons stay pending.
+struct FinishEphemerons {
+    ephemerons: Vec<ObjectReference>,
+    /// True if the values of the ephemerons have been traced.
+    resolved: bool,
+    mode: WeakProcessingMode,
+}
+
+impl GCWork<Ruby> for FinishEphemerons {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let num_ephemerons = self.ephemerons.len();
+        let mut live_ephemerons = Vec::with_capacity(num_ephemerons);
+        let mut dead_ephemerons = vec![];
+        let mut cleared = 0usize;
+
+        for ephemeron in self.ephemerons.iter().copied() {
+            if self.mode != WeakProcessingMode::Forwarding && !ephemeron.is_reachable() {
+                if self.mode == WeakProcessingMode::ClearDead {
+                    // It may be retained later.
+                    dead_ephemerons.push(ephemeron);
+                } else {
+                    trace!("Removing dead ephemeron: {ephemeron}");
+                }
+                continue;
+            }
+            live_ephemerons.push(ephemeron.forward());
+            // When forwarding, the ephemeron has not been moved, yet.
+            let ephemeron = if self.mode == WeakProcessingMode::Forwarding {
+                ephemeron
+            } else {
+                ephemeron.forward()
+            };
+
+            if self.resolved {
+                forward_slot(key_slot(ephemeron));
+                forward_slot(value_slot(ephemeron));
+            } else {
+                trace!("Clearing ephemeron: {ephemeron}");
+                unsafe {
+                    key_slot(ephemeron).store(WEAK_REF_CLEARED);
+                    value_slot(ephemeron).store(WEAK_REF_CLEARED);
+                }
+                cleared += 1;
+            }
+        }
+
+        let live = live_ephemerons.len();
+        let registry = &crate::binding().weak_proc.ephemerons;
+        if self.mode == WeakProcessingMode::ClearDead {
+            registry
+                .resolved
+                .lock()
+                .unwrap()
+                .append(&mut live_ephemerons);
+            registry
+                .pending
+                .lock()
+                .unwrap()
+                .append(&mut dead_ephemerons);
+        } else {
+            registry
+                .ephemerons
+                .lock()
+                .unwrap()
+                .append(&mut live_ephemerons);
+        }
+        probe!(mmtk_ruby, finish_ephemerons, num_ephemerons, live, cleared);
+    }
+}
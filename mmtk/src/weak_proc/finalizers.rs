@@ -0,0 +1,202 @@
+//! Finalization for `ObjectSpace.define_finalizer`.
+//!
+//! The VM registers an object together with the array of its finalizer procs.  When the object
+//! becomes unreachable, it is resurrected, and moved to the queue of objects ready for
+//! finalization, which the VM drains after GC.
+//!
+//! We don't use the finalization framework in mmtk-core.  It resurrects objects in the
+//! `FinalRefClosure` stage, before the binding processes weak tables in the `VMRefClosure` stage,
+//! so the `_id2ref` table would still map to resurrected objects.  Instead, dying objects are
+//! found and resurrected together with the objects retained for deferred `obj_free` (see
+//! `RetentionPhase`), after weak references to them are cleared.  The VM must remember the object
+//! ID of an object in its finalizer array if the finalizers need it.
+//!
+//! The arrays of finalizer procs are pinning roots, and so are the objects ready for finalization
+//! until the VM takes them.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use mmtk::util::ObjectReference;
+
+use super::Forwardable;
+
+/// An object registered with `ObjectSpace.define_finalizer`, and the array of its finalizer
+/// procs.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RubyFinalizable {
+    pub object: ObjectReference,
+    pub finalizers: ObjectReference,
+}
+
+#[derive(Default)]
+pub struct FinalizerRegistry {
+    /// Registered objects that have not been found dead, and the arrays of their finalizer procs.
+    /// An object may be registered more than once.
+    candidates: Mutex<HashMap<ObjectReference, Vec<ObjectReference>>>,
+    /// Candidates found dead in the current GC.  They are resurrected later in the same GC.
+    dying: Mutex<Vec<RubyFinalizable>>,
+    /// Resurrected objects waiting for the VM to run their finalizers.
+    ready: Mutex<Vec<RubyFinalizable>>,
+}
+
+impl FinalizerRegistry {
+    pub fn add(&self, finalizable: RubyFinalizable) {
+        self.candidates
+            .lock()
+            .unwrap()
+            .entry(finalizable.object)
+            .or_default()
+            .push(finalizable.finalizers);
+    }
+
+    /// Get one object that is ready for finalization.
+    pub fn get_ready(&self) -> Option<RubyFinalizable> {
+        self.ready.lock().unwrap().pop()
+    }
+
+    /// Unregister and return all objects, including those ready for finalization.
+    pub fn get_all(&self) -> Vec<RubyFinalizable> {
+        let candidates = std::mem::take(&mut *self.candidates.lock().unwrap());
+        let mut all = candidates
+            .into_iter()
+            .flat_map(|(object, finalizers)| Self::entries_of(object, finalizers))
+            .collect::<Vec<_>>();
+        all.append(&mut self.ready.lock().unwrap());
+        all
+    }
+
+    /// Unregister and return the entries of `object`.
+    pub fn get_for(&self, object: ObjectReference) -> Vec<RubyFinalizable> {
+        let finalizers = self.candidates.lock().unwrap().remove(&object);
+        finalizers
+            .map(|finalizers| Self::entries_of(object, finalizers).collect())
+            .unwrap_or_default()
+    }
+
+    fn entries_of(
+        object: ObjectReference,
+        finalizers: Vec<ObjectReference>,
+    ) -> impl Iterator<Item = RubyFinalizable> {
+        finalizers
+            .into_iter()
+            .map(move |finalizers| RubyFinalizable { object, finalizers })
+    }
+
+    /// Call `f` on each object that must be kept alive as a root.
+    pub fn for_each_root(&self, mut f: impl FnMut(ObjectReference)) {
+        for finalizers in self.candidates.lock().unwrap().values() {
+            finalizers.iter().copied().for_each(&mut f);
+        }
+        for finalizable in self.ready.lock().unwrap().iter() {
+            f(finalizable.object);
+            f(finalizable.finalizers);
+        }
+    }
+
+    /// Move unreachable candidates to the dying list.  Return true if any are found.
+    pub(super) fn find_dying(&self) -> bool {
+        let mut candidates = self.candidates.lock().unwrap();
+        let mut dying = self.dying.lock().unwrap();
+        let dead = candidates
+            .keys()
+            .copied()
+            .filter(|object| !object.is_reachable())
+            .collect::<Vec<_>>();
+        for object in dead {
+            let finalizers = candidates.remove(&object).unwrap();
+            dying.extend(Self::entries_of(object, finalizers));
+        }
+        debug!(
+            "Finalizable objects: {} live, {} dying",
+            candidates.len(),
+            dying.len()
+        );
+        !dying.is_empty()
+    }
+
+    pub(super) fn has_dying(&self) -> bool {
+        !self.dying.lock().unwrap().is_empty()
+    }
+
+    /// Resurrect the dying objects, and make them ready for finalization.  `trace` traces an object
+    /// and returns its new address.  Return the number of objects resurrected.
+    pub(super) fn resurrect(
+        &self,
+        mut trace: impl FnMut(ObjectReference) -> ObjectReference,
+    ) -> usize {
+        let dying = std::mem::take(&mut *self.dying.lock().unwrap());
+        let num_dying = dying.len();
+        let mut ready = self.ready.lock().unwrap();
+        for mut finalizable in dying {
+            finalizable.object = trace(finalizable.object);
+            trace!("Resurrected finalizable object: {}", finalizable.object);
+            ready.push(finalizable);
+        }
+        num_dying
+    }
+
+    /// Update the addresses of the candidates and the objects ready for finalization.  Called
+    /// after the objects that moved have been assigned new addresses.
+    pub(super) fn forward(&self) {
+        let mut candidates = self.candidates.lock().unwrap();
+        *candidates = std::mem::take(&mut *candidates)
+            .into_iter()
+            .map(|(object, finalizers)| (object.forward(), finalizers))
+            .collect();
+        for finalizable in self.ready.lock().unwrap().iter_mut() {
+            finalizable.object = finalizable.object.forward();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mmtk::util::Address;
+
+    use super::*;
+
+    fn obj(addr: usize) -> ObjectReference {
+        ObjectReference::from_raw_address(Address::from_usize(addr)).unwrap()
+    }
+
+    fn finalizable(object: usize, finalizers: usize) -> RubyFinalizable {
+        RubyFinalizable {
+            object: obj(object),
+            finalizers: obj(finalizers),
+        }
+    }
+
+    #[test]
+    fn get_for_only_takes_entries_of_the_object() {
+        let registry = FinalizerRegistry::default();
+        registry.add(finalizable(0x1000, 0x1100));
+        registry.add(finalizable(0x2000, 0x2100));
+        registry.add(finalizable(0x1000, 0x1200));
+
+        assert_eq!(registry.get_for(obj(0x3000)), vec![]);
+        assert_eq!(
+            registry.get_for(obj(0x1000)),
+            vec![finalizable(0x1000, 0x1100), finalizable(0x1000, 0x1200)]
+        );
+        assert_eq!(registry.get_all(), vec![finalizable(0x2000, 0x2100)]);
+    }
+
+    #[test]
+    fn resurrected_objects_become_ready() {
+        let registry = FinalizerRegistry::default();
+        registry
+            .dying
+            .lock()
+            .unwrap()
+            .push(finalizable(0x1000, 0x1100));
+
+        let resurrected = registry.resurrect(|o| obj(o.to_raw_address().as_usize() + 0x10));
+
+        assert_eq!(resurrected, 1);
+        assert!(!registry.has_dying());
+        assert_eq!(registry.get_ready(), Some(finalizable(0x1010, 0x1100)));
+        assert_eq!(registry.get_ready(), None);
+    }
+}
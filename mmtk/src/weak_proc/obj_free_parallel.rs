@@ -0,0 +1,207 @@
+//! Process `obj_free` candidates in parallel.
+//!
+//! The candidate list is split into chunks, and each chunk is processed by a work packet.  Live
+//! candidates are forwarded and merged back into the candidate list.  Dead candidates whose
+//! builtin types the VM declared as safe to free concurrently are freed in the chunk packets.
+//! Other dead candidates are collected and freed in one serial packet after all chunk packets
+//! finish.
+//!
+//! If `obj_free` is deferred (see `crate::deferred_obj_free`), the candidates are processed twice.
+//! The first time, dead candidates of deferrable types that need cleanup are removed from the
+//! candidate list and handed to the finalizer thread.  They are retained by `RetainDeadObjects` after weak references
+//! to them are cleared.
+//! The second time, the remaining candidates are processed as usual.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::ObjectReference;
+
+use crate::abi::RubyObjectAccess;
+use crate::utils::AfterAll;
+use crate::{upcalls, Ruby};
+
+use super::Forwardable;
+
+/// States shared by the work packets that process the candidates in one GC.
+struct ObjFreeProcessing {
+    old_cands: usize,
+    /// Dead candidates that must be freed serially.
+    serial_objects: Mutex<Vec<ObjectReference>>,
+    freed: AtomicUsize,
+    elided: AtomicUsize,
+}
+
+impl ObjFreeProcessing {
+    fn free_if_needed(&self, object: ObjectReference) {
+        if (upcalls().obj_needs_cleanup_p)(object) {
+            (upcalls().call_obj_free)(object);
+            self.freed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.elided.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(super) struct ProcessObjFreeCandidates {
+    pub(super) stage: WorkBucketStage,
+    /// Defer the `obj_free` of dead candidates of deferrable types that need cleanup to the
+    /// finalizer thread, and leave other candidates in the list.
+    pub(super) defer: bool,
+}
+
+impl GCWork<Ruby> for ProcessObjFreeCandidates {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        // Thread-local buffers have been flushed when the world stopped.  Flush again in case
+        // anything is registered after that, such as by the VM during GC.
+        let weak_proc = &crate::binding().weak_proc;
+        weak_proc.flush_all_obj_free_buffers();
+
+        let candidates = {
+            // If it blocks, it is a bug.
+            let mut obj_free_candidates = weak_proc
+                .obj_free_candidates
+                .try_lock()
+                .expect("It's GC time.  No mutators should hold this lock at this time.");
+            std::mem::take(&mut *obj_free_candidates)
+        };
+
+        let old_cands = candidates.len();
+        debug!("Total: {} candidates", old_cands);
+
+        let processing = Arc::new(ObjFreeProcessing {
+            old_cands,
+            serial_objects: Default::default(),
+            freed: AtomicUsize::new(0),
+            elided: AtomicUsize::new(0),
+        });
+
+        let after_all = Arc::new(AfterAll::new(self.stage));
+        after_all.add_packets(vec![Box::new(ProcessObjFreeCandidatesSerial {
+            processing: processing.clone(),
+        })]);
+
+        let chunk_size = crate::binding().obj_free_chunk_size;
+        let chunk_packets = candidates
+            .chunks(chunk_size)
+            .map(|chunk| {
+                Box::new(ProcessObjFreeCandidatesChunk {
+                    objects: chunk.to_vec(),
+                    defer: self.defer,
+                    processing: processing.clone(),
+                    after_all: after_all.clone(),
+                }) as _
+            })
+            .collect::<Vec<_>>();
+
+        if chunk_packets.is_empty() {
+            // Still run the serial packet so that the probe is fired.
+            after_all.count_up(1);
+            after_all.count_down(worker);
+            return;
+        }
+
+        after_all.count_up(chunk_packets.len());
+        worker.scheduler().work_buckets[self.stage].bulk_add(chunk_packets);
+    }
+}
+
+struct ProcessObjFreeCandidatesChunk {
+    objects: Vec<ObjectReference>,
+    defer: bool,
+    processing: Arc<ObjFreeProcessing>,
+    after_all: Arc<AfterAll>,
+}
+
+impl GCWork<Ruby> for ProcessObjFreeCandidatesChunk {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let weak_proc = &crate::binding().weak_proc;
+        let deferred_obj_free = &crate::binding().deferred_obj_free;
+        let mut survivors = Vec::new();
+        let mut serial_objects = Vec::new();
+        let mut deferred_objects = Vec::new();
+
+        for object in self.objects.iter().copied() {
+            if self.defer {
+                if !object.is_reachable()
+                    && deferred_obj_free.is_deferrable(RubyObjectAccess::from_objref(object))
+                    && (upcalls().obj_needs_cleanup_p)(object)
+                {
+                    deferred_objects.push(object);
+                } else {
+                    // Processed again after the deferred objects are retained.
+                    survivors.push(object);
+                }
+            } else if object.is_reachable() {
+                // Forward and add back to the candidate list.
+                let new_object = object.forward();
+                trace!(
+                    "Forwarding obj_free candidate: {} -> {}",
+                    object,
+                    new_object
+                );
+                survivors.push(new_object);
+            } else if weak_proc.is_obj_free_parallel_safe(RubyObjectAccess::from_objref(object)) {
+                self.processing.free_if_needed(object);
+            } else {
+                serial_objects.push(object);
+            }
+        }
+
+        if !deferred_objects.is_empty() {
+            // They are retained by `RetainDeadObjects` later.
+            deferred_obj_free.add_pending(&deferred_objects);
+        }
+        if !survivors.is_empty() {
+            weak_proc.add_obj_free_candidates(&survivors);
+        }
+        if !serial_objects.is_empty() {
+            let mut all_serial_objects = self.processing.serial_objects.lock().unwrap();
+            all_serial_objects.append(&mut serial_objects);
+        }
+
+        self.after_all.count_down(worker);
+    }
+}
+
+/// Free the dead candidates that are not safe to free concurrently.  It runs after all chunk
+/// packets.
+struct ProcessObjFreeCandidatesSerial {
+    processing: Arc<ObjFreeProcessing>,
+}
+
+impl GCWork<Ruby> for ProcessObjFreeCandidatesSerial {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let serial_objects = std::mem::take(
+            &mut *self
+                .processing
+                .serial_objects
+                .try_lock()
+                .expect("All chunk packets should have finished"),
+        );
+        debug!("Freeing {} candidates serially", serial_objects.len());
+
+        for object in serial_objects {
+            self.processing.free_if_needed(object);
+        }
+
+        let old_cands = self.processing.old_cands;
+        let new_cands = crate::binding()
+            .weak_proc
+            .obj_free_candidates
+            .try_lock()
+            .expect("All chunk packets should have finished")
+            .len();
+        let freed = self.processing.freed.load(Ordering::Relaxed);
+        let elided = self.processing.elided.load(Ordering::Relaxed);
+        probe!(
+            mmtk_ruby,
+            process_obj_free_candidates,
+            old_cands,
+            new_cands,
+            freed,
+            elided,
+        );
+    }
+}
@@ -0,0 +1,421 @@
+//! First-class weak references.
+//!
+//! A weak reference object is a Ruby object that holds its referent in the field at
+//! `RubyBindingOptions::weak_ref_referent_offset` bytes from the object reference.  The VM
+//! registers weak reference objects with `mmtk_register_weak_reference`, and the binding owns the
+//! referent fields.  GC does not trace the referent field.  Instead, after the transitive closure,
+//! the binding clears the field to `Qfalse` if the referent is dead, or updates it if the referent
+//! is moved.  Dead weak reference objects are removed from the registry.
+//!
+//! Soft references have the same layout, and are registered with `mmtk_register_soft_reference`.
+//! Their referents are retained during nursery and normal GCs, like ephemerons whose keys are the
+//! soft references themselves.  They are cleared like weak references during emergency GCs,
+//! exhaustive user-requested GCs other than those collecting object histograms, and full-heap GCs
+//! when the bytes live after the previous GC exceed `RUBY_MMTK_SOFT_REF_CLEAR_THRESHOLD` (a
+//! fraction of the maximum heap size).
+//!
+//! The registry is processed in parallel, one work packet per chunk of weak reference objects.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use mmtk::memory_manager;
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::options::GCTriggerSelector;
+use mmtk::util::{Address, ObjectReference};
+use mmtk::vm::{ObjectTracer, ObjectTracerContext};
+
+use crate::abi::VALUE;
+use crate::Ruby;
+
+use super::{Forwardable, WeakProcessingMode};
+
+/// The value of a cleared referent field.  It is `Qfalse`.
+pub const WEAK_REF_CLEARED: usize = 0;
+
+/// The number of weak reference objects processed in one work packet.
+const WEAK_REFS_CHUNK_SIZE: usize = 1024;
+
+fn referent_slot(reference: ObjectReference) -> Address {
+    reference.to_raw_address() + crate::binding().options.weak_ref_referent_offset
+}
+
+/// Load a field that holds a Ruby `VALUE`.  Return `None` if it is not a heap object.
+pub(super) fn load_object_field(slot: Address) -> Option<ObjectReference> {
+    unsafe { slot.load::<VALUE>() }.as_object_reference()
+}
+
+/// Get the referent of `reference`.  Return `None` if it is cleared or not a heap object.
+pub fn get_referent(reference: ObjectReference) -> Option<ObjectReference> {
+    load_object_field(referent_slot(reference))
+}
+
+pub fn set_referent(reference: ObjectReference, referent: ObjectReference) {
+    unsafe { referent_slot(reference).store(referent) }
+}
+
+pub fn clear_referent(reference: ObjectReference) {
+    unsafe { referent_slot(reference).store(WEAK_REF_CLEARED) }
+}
+
+/// Statistics of soft references.
+#[repr(C)]
+#[derive(Default)]
+pub struct SoftRefStats {
+    /// The number of GCs that cleared soft references.
+    pub clearing_gcs: usize,
+    /// The total number of soft references cleared.
+    pub cleared: usize,
+}
+
+pub struct WeakRefRegistry {
+    references: Mutex<Vec<ObjectReference>>,
+    soft_references: Mutex<Vec<ObjectReference>>,
+    /// Soft references whose referents have not been retained in the current GC.
+    soft_pending: Mutex<Vec<ObjectReference>>,
+    /// Set if any soft referent is retained in the current round.
+    soft_progress: AtomicBool,
+    /// Clear soft references if the bytes live after the previous GC exceed this fraction of the
+    /// maximum heap size.
+    soft_ref_clear_threshold: f64,
+    /// The bytes in use at the end of the previous GC.
+    live_bytes_after_last_gc: AtomicUsize,
+    /// Set by exhaustive user-requested GCs, such as `GC.start`.
+    exhaustive_gc_requested: AtomicBool,
+    soft_ref_clearing_gcs: AtomicUsize,
+    soft_refs_cleared: AtomicUsize,
+}
+
+impl WeakRefRegistry {
+    pub fn new(soft_ref_clear_threshold: f64) -> Self {
+        Self {
+            references: Default::default(),
+            soft_references: Default::default(),
+            soft_pending: Default::default(),
+            soft_progress: AtomicBool::new(false),
+            soft_ref_clear_threshold,
+            live_bytes_after_last_gc: AtomicUsize::new(0),
+            exhaustive_gc_requested: AtomicBool::new(false),
+            soft_ref_clearing_gcs: AtomicUsize::new(0),
+            soft_refs_cleared: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn register(&self, reference: ObjectReference) {
+        self.references.lock().unwrap().push(reference);
+    }
+
+    pub fn register_soft(&self, reference: ObjectReference) {
+        self.soft_references.lock().unwrap().push(reference);
+    }
+
+    fn re_add(&self, references: &[ObjectReference], soft: bool) {
+        let list = if soft {
+            &self.soft_references
+        } else {
+            &self.references
+        };
+        list.lock().unwrap().extend_from_slice(references);
+    }
+
+    /// Let the next GC clear soft references.  Called for exhaustive user-requested GCs, except the
+    /// ones that collect object histograms.
+    pub fn request_exhaustive_gc(&self) {
+        self.exhaustive_gc_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Withdraw the request if it has not been taken by a GC.  Called after a user-requested GC
+    /// returns, because the request may be ignored, and must not affect a later GC.
+    pub fn cancel_exhaustive_gc_request(&self) {
+        self.exhaustive_gc_requested.store(false, Ordering::SeqCst);
+    }
+
+    /// Record the bytes live after the current GC.  Called before resuming mutators.
+    pub fn on_gc_end(&self) {
+        let live = memory_manager::used_bytes(crate::mmtk());
+        self.live_bytes_after_last_gc.store(live, Ordering::Relaxed);
+    }
+
+    fn should_clear_soft_refs(&self) -> bool {
+        let mmtk = crate::mmtk();
+        let exhaustive = self.exhaustive_gc_requested.swap(false, Ordering::SeqCst);
+        if mmtk.is_emergency_collection() || exhaustive {
+            return true;
+        }
+
+        let is_nursery = mmtk
+            .get_plan()
+            .generational()
+            .is_some_and(|gen| gen.is_current_gc_nursery());
+        if is_nursery {
+            return false;
+        }
+
+        let live = self.live_bytes_after_last_gc.load(Ordering::Relaxed);
+        let max = max_heap_size(&mmtk.get_options().gc_trigger)
+            .unwrap_or_else(|| memory_manager::total_bytes(mmtk));
+        exceeds_threshold(live, max, self.soft_ref_clear_threshold)
+    }
+
+    /// Decide whether to clear soft references in the current GC.  If not, make all soft
+    /// references pending so that their referents are retained.  Called before the first round
+    /// of tracing conditionally live objects in a GC.
+    pub(super) fn begin_soft_rounds(&self) {
+        self.soft_progress.store(false, Ordering::SeqCst);
+        if self.should_clear_soft_refs() {
+            debug!("Clearing soft references in this GC.");
+            self.soft_ref_clearing_gcs.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let soft_references = std::mem::take(&mut *self.soft_references.lock().unwrap());
+        debug!(
+            "Retaining referents of {} soft references",
+            soft_references.len()
+        );
+        *self.soft_pending.lock().unwrap() = soft_references;
+    }
+
+    /// Return true if any soft referent has been retained since the last call.
+    pub(super) fn take_soft_progress(&self) -> bool {
+        self.soft_progress.swap(false, Ordering::SeqCst)
+    }
+
+    /// Schedule work packets to retain the referents of pending soft references that are
+    /// reachable.  Return false if there are no pending soft references.
+    pub(super) fn schedule_soft_round<C: ObjectTracerContext<Ruby>>(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        tracer_context: C,
+    ) -> bool {
+        let pending = std::mem::take(&mut *self.soft_pending.lock().unwrap());
+        if pending.is_empty() {
+            return false;
+        }
+
+        let packets = pending
+            .chunks(WEAK_REFS_CHUNK_SIZE)
+            .map(|chunk| {
+                Box::new(RetainSoftReferents {
+                    references: chunk.to_vec(),
+                    tracer_context: tracer_context.clone(),
+                }) as _
+            })
+            .collect::<Vec<_>>();
+        worker.scheduler().work_buckets[stage].bulk_add(packets);
+        true
+    }
+
+    /// Schedule work packets to process all weak and soft reference objects in `stage` according
+    /// to `mode`.
+    pub fn schedule_processing(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        mode: WeakProcessingMode,
+    ) {
+        let references = std::mem::take(
+            &mut *self
+                .references
+                .try_lock()
+                .expect("Mutators should not be holding the lock."),
+        );
+        let mut soft_references = std::mem::take(
+            &mut *self
+                .soft_references
+                .try_lock()
+                .expect("Mutators should not be holding the lock."),
+        );
+        // Soft references that are still pending were not reachable when retaining referents.
+        // They may still be retained after `ClearDead`, and their referents retained, too.
+        if mode != WeakProcessingMode::ClearDead {
+            soft_references.append(&mut self.soft_pending.lock().unwrap());
+        }
+        debug!(
+            "Processing {} weak references and {} soft references",
+            references.len(),
+            soft_references.len()
+        );
+
+        let make_packets = |references: &[ObjectReference], soft: bool| {
+            references
+                .chunks(WEAK_REFS_CHUNK_SIZE)
+                .map(|chunk| {
+                    Box::new(ProcessWeakRefsChunk {
+                        references: chunk.to_vec(),
+                        soft,
+                        mode,
+                    }) as _
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut packets = make_packets(&references, false);
+        packets.append(&mut make_packets(&soft_references, true));
+        worker.scheduler().work_buckets[stage].bulk_add(packets);
+    }
+
+    pub fn soft_ref_stats(&self) -> SoftRefStats {
+        SoftRefStats {
+            clearing_gcs: self.soft_ref_clearing_gcs.load(Ordering::Relaxed),
+            cleared: self.soft_refs_cleared.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Get the maximum heap size allowed by the GC trigger.  Return `None` if the trigger is delegated
+/// to the VM.
+fn max_heap_size(gc_trigger: &GCTriggerSelector) -> Option<usize> {
+    match *gc_trigger {
+        GCTriggerSelector::FixedHeapSize(size) => Some(size),
+        GCTriggerSelector::DynamicHeapSize(_, max) => Some(max),
+        GCTriggerSelector::Delegated => None,
+    }
+}
+
+/// Return true if `live` bytes are at least `threshold` of `max` bytes.
+fn exceeds_threshold(live: usize, max: usize, threshold: f64) -> bool {
+    let pressure = live as f64 / max as f64;
+    debug!("Heap pressure: {live} / {max} = {pressure}");
+    pressure >= threshold
+}
+
+/// Trace the referents of soft references that are reachable.
+struct RetainSoftReferents<C: ObjectTracerContext<Ruby>> {
+    references: Vec<ObjectReference>,
+    tracer_context: C,
+}
+
+impl<C: ObjectTracerContext<Ruby>> GCWork<Ruby> for RetainSoftReferents<C> {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let mut still_pending = vec![];
+        let mut retained = vec![];
+
+        self.tracer_context.with_tracer(worker, |tracer| {
+            for reference in self.references.iter().copied() {
+                // The soft reference itself may become reachable in a later round.
+                if !reference.is_reachable() {
+                    still_pending.push(reference);
+                    continue;
+                }
+
+                let reference = reference.forward();
+                if let Some(referent) = get_referent(reference) {
+                    let new_referent = tracer.trace_object(referent);
+                    trace!("Retaining soft referent of {reference}: {referent} -> {new_referent}");
+                    set_referent(reference, new_referent);
+                }
+                retained.push(reference);
+            }
+        });
+
+        let registry = &crate::binding().weak_proc.weak_refs;
+        if !retained.is_empty() {
+            registry.soft_progress.store(true, Ordering::SeqCst);
+            registry.re_add(&retained, true);
+        }
+        if !still_pending.is_empty() {
+            registry
+                .soft_pending
+                .lock()
+                .unwrap()
+                .append(&mut still_pending);
+        }
+    }
+}
+
+struct ProcessWeakRefsChunk {
+    references: Vec<ObjectReference>,
+    soft: bool,
+    mode: WeakProcessingMode,
+}
+
+impl GCWork<Ruby> for ProcessWeakRefsChunk {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let num_refs = self.references.len();
+        let mut live_refs = Vec::with_capacity(num_refs);
+        let mut cleared = 0usize;
+
+        let forwarding = self.mode == WeakProcessingMode::Forwarding;
+        for reference in self.references.iter().copied() {
+            if !forwarding && !reference.is_reachable() {
+                if self.mode == WeakProcessingMode::ClearDead {
+                    // It may be retained later.
+                    live_refs.push(reference);
+                } else {
+                    trace!("Removing dead weak reference: {reference}");
+                }
+                continue;
+            }
+            live_refs.push(reference.forward());
+            // When forwarding, the reference object has not been moved, yet.
+            let reference = if forwarding {
+                reference
+            } else {
+                reference.forward()
+            };
+
+            let Some(referent) = get_referent(reference) else {
+                continue;
+            };
+            if forwarding || referent.is_reachable() {
+                let new_referent = referent.forward();
+                if new_referent != referent {
+                    trace!("Forwarding referent of {reference}: {referent} -> {new_referent}");
+                    set_referent(reference, new_referent);
+                }
+            } else {
+                trace!("Clearing referent of {reference}: {referent}");
+                clear_referent(reference);
+                cleared += 1;
+            }
+        }
+
+        let live = live_refs.len();
+        let registry = &crate::binding().weak_proc.weak_refs;
+        registry.re_add(&live_refs, self.soft);
+        if self.soft {
+            registry
+                .soft_refs_cleared
+                .fetch_add(cleared, Ordering::Relaxed);
+        }
+        probe!(mmtk_ruby, process_weak_refs_chunk, num_refs, live, cleared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(value: usize) -> Option<ObjectReference> {
+        let slot = value;
+        load_object_field(Address::from_ref(&slot))
+    }
+
+    #[test]
+    fn special_consts_are_not_objects() {
+        // Qfalse (the cleared value), Qnil, Qtrue, Qundef, a fixnum, a flonum and a static symbol.
+        for value in [WEAK_REF_CLEARED, 0x04, 0x14, 0x24, 0x03, 0x02, 0x0c] {
+            assert_eq!(load(value), None, "{value:#x}");
+        }
+    }
+
+    #[test]
+    fn soft_ref_threshold_uses_max_heap_size() {
+        let fixed = GCTriggerSelector::FixedHeapSize(1000);
+        let dynamic = GCTriggerSelector::DynamicHeapSize(100, 1000);
+        assert_eq!(max_heap_size(&fixed), Some(1000));
+        assert_eq!(max_heap_size(&dynamic), Some(1000));
+        assert_eq!(max_heap_size(&GCTriggerSelector::Delegated), None);
+
+        assert!(!exceeds_threshold(899, 1000, 0.9));
+        assert!(exceeds_threshold(900, 1000, 0.9));
+        assert!(exceeds_threshold(0, 1000, 0.0));
+        assert!(!exceeds_threshold(1000, 1000, 1.1));
+    }
+
+    #[test]
+    fn heap_objects_are_loaded() {
+        let object = load(0x7f00_0000_1000).unwrap();
+        assert_eq!(object.to_raw_address().as_usize(), 0x7f00_0000_1000);
+    }
+}
@@ -0,0 +1,291 @@
+//! Precise weak slots.
+//!
+//! C code registers individual slots that hold weak references with `mmtk_register_weak_slot`,
+//! together with the objects that own them.  After the transitive closure, the binding clears a
+//! slot to `Qfalse` if the object in it is dead, or updates it if the object is moved, without
+//! calling into C.  Registrations are removed when their owners die, or explicitly with
+//! `mmtk_unregister_weak_slot`.
+//!
+//! A slot may be inside its owner or outside the GC heap (for example, in a `malloc`-ed table of
+//! the owner).  A slot inside its owner moves with the owner, so we remember its offset.
+//!
+//! The registry is processed in parallel, one work packet per chunk of slots.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use mmtk::memory_manager;
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::{Address, ObjectReference};
+
+use crate::abi::RubyObjectAccess;
+use crate::Ruby;
+
+use super::weak_refs::{load_object_field, WEAK_REF_CLEARED};
+use super::{Forwardable, WeakProcessingMode};
+
+/// The number of slots processed in one work packet.
+const WEAK_SLOTS_CHUNK_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SlotLocation {
+    /// The slot is inside the owner, at this offset from the object reference.
+    InOwner(usize),
+    /// The slot is outside the GC heap.
+    OffHeap(Address),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct WeakSlot {
+    owner: ObjectReference,
+    location: SlotLocation,
+}
+
+impl WeakSlot {
+    fn slot(&self) -> Address {
+        match self.location {
+            SlotLocation::InOwner(offset) => self.owner.to_raw_address() + offset,
+            SlotLocation::OffHeap(slot) => slot,
+        }
+    }
+}
+
+#[derive(Default)]
+struct WeakSlotList {
+    slots: Vec<WeakSlot>,
+    /// Maps the current address of each slot to its index in `slots`.
+    indices: HashMap<Address, usize>,
+}
+
+impl WeakSlotList {
+    /// Add `weak_slot`.  Return false if its slot is already registered.
+    fn insert(&mut self, weak_slot: WeakSlot) -> bool {
+        match self.indices.entry(weak_slot.slot()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(self.slots.len());
+                self.slots.push(weak_slot);
+                true
+            }
+        }
+    }
+
+    /// Remove the registration of `slot`.  Return false if it is not registered.
+    fn remove(&mut self, slot: Address) -> bool {
+        let Some(index) = self.indices.remove(&slot) else {
+            return false;
+        };
+        self.slots.swap_remove(index);
+        if let Some(moved) = self.slots.get(index) {
+            self.indices.insert(moved.slot(), index);
+        }
+        true
+    }
+
+    fn take(&mut self) -> Vec<WeakSlot> {
+        self.indices.clear();
+        std::mem::take(&mut self.slots)
+    }
+}
+
+#[derive(Default)]
+pub struct WeakSlotRegistry {
+    slots: Mutex<WeakSlotList>,
+}
+
+impl WeakSlotRegistry {
+    /// Register `slot` as a weak slot owned by `owner`.  Return false, and do nothing, if `slot`
+    /// is already registered.
+    pub fn register(&self, owner: ObjectReference, slot: Address) -> bool {
+        let acc = RubyObjectAccess::from_objref(owner);
+        let location = if acc.payload_addr() <= slot && slot < acc.obj_end() {
+            SlotLocation::InOwner(slot - owner.to_raw_address())
+        } else {
+            debug_assert!(
+                slot < memory_manager::starting_heap_address()
+                    || slot >= memory_manager::last_heap_address(),
+                "Weak slot {slot} is in the heap, but not in its owner {owner}"
+            );
+            SlotLocation::OffHeap(slot)
+        };
+        trace!("Registering weak slot {slot} of {owner}: {location:?}");
+        let registered = self
+            .slots
+            .lock()
+            .unwrap()
+            .insert(WeakSlot { owner, location });
+        if !registered {
+            warn!("Weak slot {slot} is already registered.  Ignored registration by {owner}.");
+        }
+        registered
+    }
+
+    /// Unregister `slot`.  Return false if it is not registered.
+    pub fn unregister(&self, slot: Address) -> bool {
+        trace!("Unregistering weak slot {slot}");
+        self.slots.lock().unwrap().remove(slot)
+    }
+
+    fn re_add(&self, slots: &[WeakSlot]) {
+        let mut list = self.slots.lock().unwrap();
+        for weak_slot in slots.iter().copied() {
+            let inserted = list.insert(weak_slot);
+            debug_assert!(inserted, "Duplicated weak slot: {weak_slot:?}");
+        }
+    }
+
+    /// Schedule work packets to process all weak slots in `stage` according to `mode`.
+    pub fn schedule_processing(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        stage: WorkBucketStage,
+        mode: WeakProcessingMode,
+    ) {
+        let slots = self
+            .slots
+            .try_lock()
+            .expect("Mutators should not be holding the lock.")
+            .take();
+        debug!("Processing {} weak slots", slots.len());
+
+        let packets = slots
+            .chunks(WEAK_SLOTS_CHUNK_SIZE)
+            .map(|chunk| {
+                Box::new(ProcessWeakSlotsChunk {
+                    slots: chunk.to_vec(),
+                    mode,
+                }) as _
+            })
+            .collect::<Vec<_>>();
+        worker.scheduler().work_buckets[stage].bulk_add(packets);
+    }
+}
+
+struct ProcessWeakSlotsChunk {
+    slots: Vec<WeakSlot>,
+    mode: WeakProcessingMode,
+}
+
+impl GCWork<Ruby> for ProcessWeakSlotsChunk {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let num_slots = self.slots.len();
+        let mut live_slots = Vec::with_capacity(num_slots);
+        let mut cleared = 0usize;
+
+        let forwarding = self.mode == WeakProcessingMode::Forwarding;
+        for weak_slot in self.slots.iter() {
+            if !forwarding && !weak_slot.owner.is_reachable() {
+                if self.mode == WeakProcessingMode::ClearDead {
+                    // The owner may be retained later.
+                    live_slots.push(*weak_slot);
+                } else {
+                    trace!("Removing weak slot of dead owner: {:?}", weak_slot);
+                }
+                continue;
+            }
+            let forwarded_slot = WeakSlot {
+                owner: weak_slot.owner.forward(),
+                location: weak_slot.location,
+            };
+            live_slots.push(forwarded_slot);
+
+            // When forwarding, the owner has not been moved, yet.
+            let slot = if forwarding {
+                weak_slot.slot()
+            } else {
+                forwarded_slot.slot()
+            };
+            let Some(target) = load_object_field(slot) else {
+                continue;
+            };
+            if forwarding || target.is_reachable() {
+                let new_target = target.forward();
+                if new_target != target {
+                    trace!("Forwarding weak slot {slot}: {target} -> {new_target}");
+                    unsafe { slot.store(new_target) };
+                }
+            } else {
+                trace!("Clearing weak slot {slot}: {target}");
+                unsafe { slot.store(WEAK_REF_CLEARED) };
+                cleared += 1;
+            }
+        }
+
+        let live = live_slots.len();
+        crate::binding().weak_proc.weak_slots.re_add(&live_slots);
+        probe!(
+            mmtk_ruby,
+            process_weak_slots_chunk,
+            num_slots,
+            live,
+            cleared
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obj(addr: usize) -> ObjectReference {
+        ObjectReference::from_raw_address(Address::from_usize(addr)).unwrap()
+    }
+
+    fn in_owner(owner: usize, offset: usize) -> WeakSlot {
+        WeakSlot {
+            owner: obj(owner),
+            location: SlotLocation::InOwner(offset),
+        }
+    }
+
+    fn off_heap(owner: usize, slot: usize) -> WeakSlot {
+        WeakSlot {
+            owner: obj(owner),
+            location: SlotLocation::OffHeap(Address::from_usize(slot)),
+        }
+    }
+
+    #[test]
+    fn slot_in_owner_moves_with_owner() {
+        assert_eq!(in_owner(0x1000, 0x18).slot(), Address::from_usize(0x1018));
+        assert_eq!(in_owner(0x2000, 0x18).slot(), Address::from_usize(0x2018));
+        assert_eq!(off_heap(0x1000, 0x9000).slot(), Address::from_usize(0x9000));
+    }
+
+    #[test]
+    fn duplicated_slots_are_rejected() {
+        let mut list = WeakSlotList::default();
+        assert!(list.insert(in_owner(0x1000, 0x18)));
+        assert!(list.insert(off_heap(0x1000, 0x9000)));
+        assert!(!list.insert(in_owner(0x1000, 0x18)));
+        assert!(!list.insert(off_heap(0x2000, 0x9000)));
+        assert_eq!(list.slots.len(), 2);
+    }
+
+    #[test]
+    fn unregistered_slots_are_removed() {
+        let mut list = WeakSlotList::default();
+        list.insert(in_owner(0x1000, 0x18));
+        list.insert(off_heap(0x1000, 0x9000));
+
+        assert!(list.remove(Address::from_usize(0x1018)));
+        assert!(!list.remove(Address::from_usize(0x1018)));
+        assert_eq!(list.take(), vec![off_heap(0x1000, 0x9000)]);
+        assert!(list.insert(in_owner(0x1000, 0x18)));
+    }
+
+    #[test]
+    fn removal_keeps_indices_of_other_slots() {
+        let mut list = WeakSlotList::default();
+        list.insert(in_owner(0x1000, 0x18));
+        list.insert(off_heap(0x1000, 0x9000));
+        list.insert(off_heap(0x2000, 0xa000));
+
+        // The last slot is moved to the removed position.
+        assert!(list.remove(Address::from_usize(0x1018)));
+        assert!(list.remove(Address::from_usize(0xa000)));
+        assert!(!list.remove(Address::from_usize(0xa000)));
+        assert_eq!(list.take(), vec![off_heap(0x1000, 0x9000)]);
+    }
+}
@@ -183,6 +183,15 @@ def enrich_meta_extra(log_processor, name, tid, ts, gc, wp, args):
                     },
                 }
 
+            case "clear_backwarding_table":
+                num_entries, num_shards = [int(x) for x in args[0:2]]
+                wp["args"] |= {
+                    "backwarding_table": {
+                        "entries": num_entries,
+                        "shards": num_shards,
+                    },
+                }
+
             case "process_weak_references":
                 num_objects, live = [int(x) for x in args[0:2]]
                 wp["args"] |= {
@@ -190,3 +199,36 @@ def enrich_meta_extra(log_processor, name, tid, ts, gc, wp, args):
                     "live": live,
                     "dead": num_objects - live,
                 }
+
+            case "process_weak_refs_chunk":
+                num_refs, live, cleared = [int(x) for x in args[0:3]]
+                wp["args"] |= {
+                    "weak_refs": {
+                        "total": num_refs,
+                        "live": live,
+                        "dead": num_refs - live,
+                        "cleared": cleared,
+                    },
+                }
+
+            case "process_weak_slots_chunk":
+                num_slots, live, cleared = [int(x) for x in args[0:3]]
+                wp["args"] |= {
+                    "weak_slots": {
+                        "total": num_slots,
+                        "live": live,
+                        "dead": num_slots - live,
+                        "cleared": cleared,
+                    },
+                }
+
+            case "finish_ephemerons":
+                num_ephemerons, live, cleared = [int(x) for x in args[0:3]]
+                wp["args"] |= {
+                    "ephemerons": {
+                        "total": num_ephemerons,
+                        "live": live,
+                        "dead": num_ephemerons - live,
+                        "cleared": cleared,
+                    },
+                }